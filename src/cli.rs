@@ -1,28 +1,277 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::Path;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::config::{LogLevel, ProxyConfig};
+use crate::install::Browser;
+use crate::messages::ReqMessage;
+use crate::requests::NodeCertificate;
+use crate::routing::Route;
+
 #[derive(Default)]
 pub struct CliOptions {
+    pub command: Command,
     pub log_file: Option<String>,
     pub tor_dir: Option<String>,
     pub debug_mode: bool,
+    pub log_level: Option<LogLevel>,
+    pub config_file: Option<String>,
+    pub use_bridges: bool,
+    pub bridges: Vec<String>,
+    pub transport_plugins: HashMap<String, String>,
+    pub proxy: Option<ProxyConfig>,
+    pub use_system_tor: bool,
+    pub default_route: Option<Route>,
+    pub socks_port: Option<u16>,
+    pub profile: Option<String>,
+    pub daemon: bool,
+    pub takeover: bool,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq, Default)]
+pub enum Command {
+    /// Handle native messages of the browser on stdin/stdout (default)
+    #[default]
+    Serve,
+    /// Write the native messaging manifests of the browsers (Linux)
+    Install(InstallOptions),
+    /// Remove the native messaging manifests of the browsers (Linux)
+    Uninstall(UninstallOptions),
+    /// Check the manifests of the browsers, the log file, the Tor folder and the running instance
+    Doctor(DoctorOptions),
+    /// Send one request the way the extension does and print the response as JSON
+    Request(RequestOptions),
+    /// Pipe a captured session into the host and print its responses as JSON lines
+    Replay(ReplayOptions),
+}
+
+#[derive(Args, Debug, Clone, PartialEq, Default)]
+pub struct ReplayOptions {
+    /// Captured session: native messaging frames, JSON lines or a JSON array of messages
+    #[clap(value_name = "FILE")]
+    pub file: String,
+    /// Host executable [default: this executable]
+    #[clap(long, value_name = "PATH")]
+    pub host: Option<String>,
+    /// Arguments of the host, after --
+    #[clap(last = true, value_name = "HOST_ARGS")]
+    pub host_args: Vec<String>,
+}
+
+#[derive(Args, Debug, Clone, PartialEq, Default)]
+pub struct RequestOptions {
+    /// URL of the node
+    #[clap(long)]
+    pub url: String,
+    /// HTTP method: GET, POST, PUT or DELETE
+    #[clap(long, default_value = "GET")]
+    pub method: String,
+    /// Request header, can be repeated
+    #[clap(long = "header", short = 'H', value_name = "NAME: VALUE", value_parser = parse_header)]
+    pub headers: Vec<(String, String)>,
+    /// Request body
+    #[clap(long)]
+    pub body: Option<String>,
+    /// Certificate of the node: base64 encoded DER, PEM, or the path of a DER or PEM file
+    #[clap(long = "cert", value_name = "CERT")]
+    pub certificate: Option<String>,
+    /// ID of the message
+    #[clap(long, default_value = "cli")]
+    pub id: String,
+}
+
+impl RequestOptions {
+    pub fn get_message(&self) -> Result<ReqMessage, String> {
+        let certificate = match &self.certificate {
+            Some(cert) if Path::new(cert).is_file() => {
+                let bytes = std::fs::read(cert).map_err(|e| format!("Can not read certificate {}: {}", cert, e))?;
+                match String::from_utf8(bytes) {
+                    Ok(pem) if pem.contains("-----BEGIN") => Some(pem),
+                    Ok(text) => Some(text.trim().to_string()),
+                    Err(err) => Some(base64::encode(err.into_bytes())),
+                }
+            },
+            other => other.clone(),
+        };
+        if let Some(cert) = &certificate {
+            if NodeCertificate::parse(cert).is_none() {
+                return Err(String::from("Certificate is neither base64 encoded DER nor PEM"));
+            }
+        }
+        Ok(ReqMessage {
+            id: self.id.clone(),
+            url: self.url.clone(),
+            method: self.method.to_uppercase(),
+            body: self.body.clone(),
+            headers: match self.headers.is_empty() {
+                true => None,
+                false => Some(self.headers.iter().cloned().collect()),
+            },
+            certificate,
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Args, Debug, Clone, PartialEq, Default)]
+pub struct InstallOptions {
+    /// Browser: firefox, chrome, chromium, brave, vivaldi or edge, can be repeated [default: the browsers found]
+    #[clap(long = "browser", value_name = "NAME", value_parser = Browser::parse)]
+    pub browsers: Vec<Browser>,
+    /// Allowed Firefox extension ID, can be repeated [default: extension@getalby.com]
+    #[clap(long = "extension-id", value_name = "ID")]
+    pub extension_ids: Vec<String>,
+    /// Allowed Chrome extension origin or ID, can be repeated [default: the Alby extension]
+    #[clap(long = "origin", value_name = "ORIGIN")]
+    pub origins: Vec<String>,
+    /// Print what would change without writing
+    #[clap(long = "dry-run")]
+    pub dry_run: bool,
+}
+
+#[derive(Args, Debug, Clone, PartialEq, Default)]
+pub struct DoctorOptions {
+    /// Print the report as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Args, Debug, Clone, PartialEq, Default)]
+pub struct UninstallOptions {
+    /// Browser, can be repeated [default: all]
+    #[clap(long = "browser", value_name = "NAME", value_parser = Browser::parse)]
+    pub browsers: Vec<Browser>,
+    /// Print what would be removed without removing
+    #[clap(long = "dry-run")]
+    pub dry_run: bool,
+}
+
+/// The Alby companion app allows Alby to connect to nodes that run behind Tor or are otherwise not easily accessible.
+#[derive(Parser, Debug)]
+#[clap(name = "alby", version)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Log file
+    #[clap(long = "log-file", short = 'l', alias = "log_file", value_name = "PATH", env = "ALBY_LOG_FILE", global = true)]
+    log_file: Option<String>,
+    /// Tor data folder
+    #[clap(long = "tor-dir", short = 't', alias = "tor_dir", value_name = "PATH", env = "ALBY_TOR_DIR", global = true)]
+    tor_dir: Option<String>,
+    /// Print debug messages to stderr and log message contents, as --log-level=debug
+    #[clap(long, global = true)]
+    debug: bool,
+    /// Log level: info or debug
+    #[clap(long = "log-level", value_name = "LEVEL", value_parser = parse_log_level, env = "ALBY_LOG_LEVEL", global = true)]
+    log_level: Option<LogLevel>,
+    /// Config file [default: config.json in the per-user config folder]
+    #[clap(long, short = 'c', value_name = "PATH", env = "ALBY_CONFIG", global = true)]
+    config: Option<String>,
+    /// Instance profile with its own log file and Tor folder [env: ALBY_PROFILE]
+    #[clap(long, value_name = "NAME", global = true)]
+    profile: Option<String>,
+
+    /// Connect to Tor through bridges
+    #[clap(long = "use-bridges", alias = "use_bridges", global = true)]
+    use_bridges: bool,
+    /// Bridge line, can be repeated
+    #[clap(long = "bridge", value_name = "LINE", global = true)]
+    bridges: Vec<String>,
+    /// Pluggable transport executable, e.g. obfs4:/usr/bin/obfs4proxy
+    #[clap(long = "transport-plugin", alias = "transport_plugin", value_name = "NAME:PATH", value_parser = parse_transport_plugin, global = true)]
+    transport_plugins: Vec<(String, String)>,
+    /// SOCKS proxy of a running Tor
+    #[clap(long, value_name = "HOST:PORT", value_parser = parse_proxy_address, env = "ALBY_PROXY", global = true)]
+    proxy: Option<(String, u16)>,
+    /// Username of the SOCKS proxy
+    #[clap(long = "proxy-username", alias = "proxy_username", value_name = "USERNAME", global = true)]
+    proxy_username: Option<String>,
+    /// Password of the SOCKS proxy
+    #[clap(long = "proxy-password", alias = "proxy_password", value_name = "PASSWORD", global = true)]
+    proxy_password: Option<String>,
+    /// Use a running system Tor or Tor Browser (ports 9050 and 9150)
+    #[clap(long = "system-tor", alias = "system_tor", global = true)]
+    system_tor: bool,
+    /// Route of the requests which don't match any routing rule: tor, direct or auto
+    #[clap(long, value_name = "ROUTE", value_parser = parse_route, env = "ALBY_ROUTE", global = true)]
+    route: Option<Route>,
+    /// SOCKS port of the embedded Tor, `auto` lets Tor choose it
+    #[clap(long = "socks-port", alias = "socks_port", value_name = "PORT", value_parser = parse_socks_port, env = "ALBY_SOCKS_PORT", global = true)]
+    socks_port: Option<u16>,
+    /// Run as the daemon shared by the browsers
+    #[clap(long, global = true)]
+    daemon: bool,
+    /// Ask the running instance to shut down instead of answering with the conflict
+    #[clap(long, global = true)]
+    takeover: bool,
+
+    /// Window of the calling Chrome on Windows
+    #[clap(long = "parent-window", hide = true)]
+    parent_window: Option<String>,
+    /// Manifest path and extension ID (Firefox) or origin (Chrome) passed by the browser
+    #[clap(hide = true)]
+    browser_args: Vec<String>,
 }
 
 pub fn get_args_from_cli() -> std::env::Args {
     std::env::args()
 }
 
+/// Exits with the usage on invalid arguments, `--help` and `--version`.
 pub fn get_cli_options(args: impl Iterator<Item=String>) -> CliOptions {
-    let mut opts: CliOptions = Default::default();
-    for arg in args {
-        if arg.starts_with("--log_file=") || arg.starts_with("--log-file=") || arg.starts_with("-l=") {
-            opts.log_file = get_arg_val(&arg);
-        }
-        if arg.starts_with("--tor_dir=") || arg.starts_with("--tor-dir=") || arg.starts_with("-t=") {
-            opts.tor_dir = get_arg_val(&arg);
-        }
-        if arg.starts_with("--debug") || arg.starts_with("-debug") {
-            opts.debug_mode = true;
-        }
+    match try_get_cli_options(args) {
+        Ok(opts) => opts,
+        Err(err) => err.exit(),
+    }
+}
+
+pub fn try_get_cli_options(args: impl Iterator<Item=String>) -> Result<CliOptions, clap::Error> {
+    let mut args: Vec<OsString> = args.filter(|a| !a.is_empty()).map(normalize_arg).collect();
+    // the program name may be omitted
+    if args.first().map(|a| a.to_string_lossy().starts_with('-')).unwrap_or(true) {
+        args.insert(0, OsString::from("alby"));
+    }
+    let cli = Cli::try_parse_from(args)?;
+    let mut proxy = cli.proxy.map(|(host, port)| ProxyConfig { host, port, ..Default::default() });
+    match &mut proxy {
+        Some(proxy) => {
+            proxy.username = cli.proxy_username;
+            proxy.password = cli.proxy_password;
+        },
+        None if cli.proxy_username.is_some() || cli.proxy_password.is_some() => {
+            eprintln!("Proxy credentials are ignored: --proxy=HOST:PORT is missing");
+        },
+        None => {},
+    }
+    Ok(CliOptions {
+        command: cli.command.unwrap_or_default(),
+        log_file: cli.log_file,
+        tor_dir: cli.tor_dir,
+        debug_mode: cli.debug,
+        log_level: cli.log_level,
+        config_file: cli.config,
+        use_bridges: cli.use_bridges,
+        bridges: cli.bridges,
+        transport_plugins: cli.transport_plugins.into_iter().collect(),
+        proxy,
+        use_system_tor: cli.system_tor,
+        default_route: cli.route,
+        socks_port: cli.socks_port,
+        profile: cli.profile,
+        daemon: cli.daemon,
+        takeover: cli.takeover,
+    })
+}
+
+/// `-debug` of the old versions is kept working
+fn normalize_arg(arg: String) -> OsString {
+    match arg.as_str() {
+        "-debug" => OsString::from("--debug"),
+        _ => OsString::from(arg),
     }
-    opts
 }
 
 #[allow(dead_code)]
@@ -30,10 +279,49 @@ pub fn get_args_from_string(s: &str) -> impl Iterator<Item=String> + '_ {
     s.split(char::is_whitespace).map(|v| v.to_string())
 }
 
-fn get_arg_val(arg: &str) -> Option<String> {
-    let parts: Vec<&str> = arg.split('=').collect();
-    if let Some(val) = parts.get(1) {
-        return Some(val.to_string());
+fn parse_transport_plugin(val: &str) -> Result<(String, String), String> {
+    match val.split_once(':') {
+        Some((transport, path)) if !transport.is_empty() && !path.is_empty() => Ok((transport.to_string(), path.to_string())),
+        _ => Err(String::from("expected NAME:PATH, e.g. obfs4:/usr/bin/obfs4proxy")),
+    }
+}
+
+fn parse_proxy_address(val: &str) -> Result<(String, u16), String> {
+    let (host, port) = val.rsplit_once(':').ok_or_else(|| String::from("expected HOST:PORT"))?;
+    let port = port.parse::<u16>().map_err(|_| format!("invalid port {}", port))?;
+    if port == 0 {
+        return Err(String::from("port can not be 0"));
     }
-    None
-}
\ No newline at end of file
+    Ok((host.trim_start_matches('[').trim_end_matches(']').to_string(), port))
+}
+
+fn parse_header(val: &str) -> Result<(String, String), String> {
+    match val.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => Ok((name.trim().to_string(), value.trim().to_string())),
+        _ => Err(String::from("expected NAME: VALUE")),
+    }
+}
+
+fn parse_route(val: &str) -> Result<Route, String> {
+    match val {
+        "tor" => Ok(Route::Tor),
+        "direct" => Ok(Route::Direct),
+        "auto" => Ok(Route::Auto),
+        _ => Err(String::from("expected tor, direct or auto")),
+    }
+}
+
+fn parse_log_level(val: &str) -> Result<LogLevel, String> {
+    match val {
+        "info" => Ok(LogLevel::Info),
+        "debug" => Ok(LogLevel::Debug),
+        _ => Err(String::from("expected info or debug")),
+    }
+}
+
+fn parse_socks_port(val: &str) -> Result<u16, String> {
+    match val {
+        "auto" => Ok(0),
+        _ => val.parse::<u16>().map_err(|_| String::from("expected a number or auto")),
+    }
+}
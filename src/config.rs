@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value as SerdeValue;
+
+use crate::routing::RoutingConfig;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Config {
+    pub log: LogConfig,
+    /// Tor data folder, see `--tor-dir`
+    pub tor_dir: Option<String>,
+    pub timeouts: TimeoutConfig,
+    /// Chrome extension origins written by `alby install` and checked by `alby doctor`, the Alby extension by default
+    pub allowed_origins: Vec<String>,
+    /// Firefox extension IDs, as `allowed_origins`
+    pub allowed_extensions: Vec<String>,
+    pub tor: TorConfig,
+    pub routing: RoutingConfig,
+    pub daemon: DaemonConfig,
+    pub instance: InstanceConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LogConfig {
+    /// See `--log-file`
+    pub file: Option<String>,
+    pub level: LogLevel,
+}
+
+/// `debug` prints the log to stderr too and logs the contents of the messages
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    #[default]
+    Info,
+    Debug,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct TimeoutConfig {
+    /// Seconds for a request to the node, including the connection through Tor
+    pub request: u64,
+    /// Seconds to wait for the embedded Tor to bootstrap
+    pub tor_bootstrap: u64,
+}
+
+impl TimeoutConfig {
+    pub fn get_request(&self) -> Duration {
+        Duration::from_secs(self.request)
+    }
+
+    pub fn get_tor_bootstrap(&self) -> Duration {
+        Duration::from_secs(self.tor_bootstrap)
+    }
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> TimeoutConfig {
+        TimeoutConfig {
+            request: 75,
+            tor_bootstrap: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct InstanceConfig {
+    /// Shut down the instance holding the lock instead of answering with the conflict
+    pub takeover: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DaemonConfig {
+    /// Browsers share one background companion (and its Tor) instead of launching their own
+    pub enabled: bool,
+    /// Seconds the daemon keeps running without connected browsers, 0 to run until it is stopped
+    pub idle_timeout: u64,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> DaemonConfig {
+        DaemonConfig {
+            enabled: false,
+            idle_timeout: 600,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct TorConfig {
+    pub use_bridges: bool,
+    pub bridges: Vec<String>,
+    /// Transport name (`obfs4`, `snowflake`, ...) => path to the plugin executable
+    pub transport_plugins: HashMap<String, String>,
+    /// SOCKS proxy of an already running Tor, used instead of the embedded one
+    pub proxy: Option<ProxyConfig>,
+    /// Look for a running Tor (system Tor on 9050, Tor Browser on 9150) before launching the embedded one
+    pub use_system_tor: bool,
+    /// SOCKS port of the embedded Tor: random free port by default, 0 lets Tor choose it
+    pub socks_port: Option<u16>,
+    /// Unix platforms: SOCKS listener of the embedded Tor on a unix socket instead of a TCP port,
+    /// enabled by default and ignored when `socks_port` is set
+    pub socks_unix_socket: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ProxyConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+pub fn load_config(path: &str) -> Result<Config, String> {
+    if !Path::new(path).exists() {
+        return Ok(Default::default());
+    }
+    let content = fs::read_to_string(path).map_err(|e| format!("Can not read config file {}: {}", path, e))?;
+    let config: Config = serde_json::from_str(&content).map_err(|e| format!("Can not parse config file {}: {}", path, e))?;
+    config.validate().map_err(|e| format!("Invalid config file {}: {}", path, e))?;
+    Ok(config)
+}
+
+pub fn save_config(path: &str, config: &Config) -> Result<(), String> {
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Can not create config folder {}: {}", dir.to_string_lossy(), e))?;
+    }
+    let content = serde_json::to_string_pretty(config).map_err(|e| format!("Can not serialize config: {}", e))?;
+    fs::write(path, content).map_err(|e| format!("Can not write config file {}: {}", path, e))
+}
+
+/// Merges the fields of `patch` into the config, objects recursively: `{"routing": {"defaultRoute": "tor"}}` keeps the other fields.
+pub fn merge_config(config: &Config, patch: SerdeValue) -> Result<Config, String> {
+    if !patch.is_object() {
+        return Err(String::from("Config must be a JSON object"));
+    }
+    let mut value = serde_json::to_value(config).map_err(|e| format!("Can not serialize config: {}", e))?;
+    merge_json(&mut value, patch);
+    let config: Config = serde_json::from_value(value).map_err(|e| format!("Invalid config: {}", e))?;
+    config.validate().map_err(|e| format!("Invalid config: {}", e))?;
+    Ok(config)
+}
+
+impl Config {
+    /// Checks the values the parser accepts but alby can't work with
+    pub fn validate(&self) -> Result<(), String> {
+        // every request or every wait for Tor would fail right away
+        if self.timeouts.request == 0 || self.timeouts.tor_bootstrap == 0 {
+            return Err(String::from("timeouts must be at least 1 second"));
+        }
+        Ok(())
+    }
+}
+
+fn merge_json(value: &mut SerdeValue, patch: SerdeValue) {
+    match (value, patch) {
+        (SerdeValue::Object(fields), SerdeValue::Object(patch)) => {
+            for (key, val) in patch {
+                match fields.get_mut(&key) {
+                    Some(field) if field.is_object() => merge_json(field, val),
+                    _ => {
+                        fields.insert(key, val);
+                    },
+                }
+            }
+        },
+        (value, patch) => *value = patch,
+    }
+}
+
+impl TorConfig {
+    /// Transports used by the bridge lines, e.g. `obfs4` for `obfs4 1.2.3.4:443 FINGERPRINT cert=...`
+    pub fn get_bridge_transports(&self) -> Vec<String> {
+        let mut transports: Vec<String> = self.bridges.iter()
+            .filter_map(|line| line.split_whitespace().next())
+            .filter(|first| !first.contains(':') && !first.contains('.'))
+            .map(|t| t.to_string())
+            .collect();
+        transports.sort();
+        transports.dedup();
+        transports
+    }
+}
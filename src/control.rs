@@ -0,0 +1,167 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::Duration;
+
+/// File where Tor writes the address of its control port (`ControlPortWriteToFile`)
+pub const CONTROL_PORT_FILE: &str = "control-port";
+/// Cookie for the control port authentication (`CookieAuthentication`)
+pub const CONTROL_AUTH_COOKIE_FILE: &str = "control_auth_cookie";
+
+/// Minimal client of the Tor control protocol:
+/// https://gitweb.torproject.org/torspec.git/tree/control-spec.txt
+pub struct TorControl {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    /// Asynchronous events (`650`) received while waiting for a command reply
+    events: VecDeque<Reply>,
+}
+
+#[derive(Debug)]
+pub struct Reply {
+    pub status: u16,
+    pub lines: Vec<String>,
+}
+
+impl TorControl {
+    /// Connects to the control port of the Tor which uses `tor_dir` and authenticates with its cookie.
+    pub fn connect(tor_dir: &str) -> Result<TorControl, String> {
+        let port_file = Path::new(tor_dir).join(CONTROL_PORT_FILE);
+        let port_content = fs::read_to_string(&port_file)
+            .map_err(|e| format!("Can not read Tor control port file {}: {}", port_file.to_string_lossy(), e))?;
+        // PORT=127.0.0.1:9051
+        let addr = port_content.lines()
+            .find_map(|line| line.trim().strip_prefix("PORT="))
+            .ok_or_else(|| format!("Tor control port is not found in {}", port_file.to_string_lossy()))?
+            .to_string();
+        let stream = TcpStream::connect(&addr).map_err(|e| format!("Can not connect to Tor control port {}: {}", &addr, e))?;
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(30)));
+        let writer = stream.try_clone().map_err(|e| format!("Can not use Tor control connection: {}", e))?;
+        let mut control = TorControl { reader: BufReader::new(stream), writer, events: VecDeque::new() };
+
+        let cookie_file = Path::new(tor_dir).join(CONTROL_AUTH_COOKIE_FILE);
+        let cookie = fs::read(&cookie_file)
+            .map_err(|e| format!("Can not read Tor control cookie {}: {}", cookie_file.to_string_lossy(), e))?;
+        control.command(&format!("AUTHENTICATE {}", to_hex(&cookie)))?;
+        Ok(control)
+    }
+
+    /// Sends a command and returns the lines of a successful reply.
+    pub fn command(&mut self, command: &str) -> Result<Reply, String> {
+        // a line break would start another command
+        if command.contains(&['\r', '\n'][..]) {
+            return Err(String::from("Tor control command must be a single line"));
+        }
+        self.writer.write_all(format!("{}\r\n", command).as_bytes())
+            .map_err(|e| format!("Can not send Tor control command: {}", e))?;
+        let mut reply = self.read_reply()?;
+        while reply.status == 650 {
+            self.events.push_back(reply);
+            reply = self.read_reply()?;
+        }
+        if reply.status != 250 {
+            return Err(format!("Tor control command failed: {} {}", reply.status, reply.lines.join(" ")));
+        }
+        Ok(reply)
+    }
+
+    /// Waits for the next event of the ones subscribed with `SETEVENTS`.
+    pub fn read_event(&mut self) -> Result<Reply, String> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(event);
+        }
+        let _ = self.reader.get_ref().set_read_timeout(None);
+        loop {
+            let reply = self.read_reply()?;
+            if reply.status == 650 {
+                return Ok(reply);
+            }
+        }
+    }
+
+    /// Reads one reply: `250-line`, `250+data` (followed by a data block ending with `.`), `250 last line`.
+    pub fn read_reply(&mut self) -> Result<Reply, String> {
+        let mut lines = vec![];
+        loop {
+            let line = self.read_line()?;
+            if line.len() < 4 {
+                return Err(format!("Unexpected Tor control reply: {}", line));
+            }
+            let status = line[..3].parse::<u16>().map_err(|_| format!("Unexpected Tor control reply: {}", line))?;
+            let separator = &line[3..4];
+            lines.push(line[4..].to_string());
+            if separator == "+" {
+                loop {
+                    let data = self.read_line()?;
+                    if data == "." {
+                        break;
+                    }
+                    lines.push(data);
+                }
+            }
+            if separator == " " {
+                return Ok(Reply { status, lines });
+            }
+        }
+    }
+
+    fn read_line(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Err(String::from("Tor control connection is closed")),
+            Ok(_) => Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string()),
+            Err(e) => Err(format!("Can not read Tor control reply: {}", e)),
+        }
+    }
+}
+
+/// `NOTICE BOOTSTRAP PROGRESS=50 SUMMARY="Loading relay descriptors"` => {PROGRESS: 50, SUMMARY: Loading relay descriptors}
+pub fn parse_keywords(line: &str) -> HashMap<String, String> {
+    let mut keywords = HashMap::new();
+    let mut rest = line.trim();
+    while !rest.is_empty() {
+        let end = rest.find(' ').unwrap_or(rest.len());
+        let eq = match rest.find('=') {
+            Some(eq) if eq < end => eq,
+            _ => {
+                rest = rest[end..].trim_start();
+                continue;
+            }
+        };
+        let key = rest[..eq].to_string();
+        rest = &rest[eq + 1..];
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut consumed = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => if let Some((_, escaped)) = chars.next() {
+                        value.push(escaped);
+                    },
+                    '"' => {
+                        consumed = i + 1;
+                        break;
+                    },
+                    _ => value.push(c),
+                }
+            }
+            rest = &quoted[consumed..];
+            value
+        } else {
+            let end = rest.find(' ').unwrap_or(rest.len());
+            let value = rest[..end].to_string();
+            rest = &rest[end..];
+            value
+        };
+        keywords.insert(key, value);
+        rest = rest.trim_start();
+    }
+    keywords
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
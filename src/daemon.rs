@@ -0,0 +1,292 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrome_native_messaging::{read_input, write_output, Error as NativeError};
+use rand::{Rng, thread_rng};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as SerdeValue;
+
+use crate::{write_debug, write_debug_to};
+use crate::messages::get_internal_msg;
+
+/// How long a browser waits for the daemon it has launched
+const DAEMON_START_TIMEOUT: Duration = Duration::from_secs(10);
+/// Clients must authenticate right after connecting
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Content of the daemon file, readable only by the user
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DaemonEndpoint {
+    pub port: u16,
+    pub token: String,
+    pub pid: u32,
+}
+
+#[derive(Deserialize)]
+struct DaemonAuth {
+    token: String,
+}
+
+/// A job for the daemon: the native message and where to send the reply
+pub type Job = (SerdeValue, Sender<String>);
+
+/// Runs the companion for all browsers: each line from an authenticated client is a native message,
+/// its response is written back as one line. Every message is handled on its own thread,
+/// so a slow request of one browser doesn't hold up the others.
+pub fn serve() -> Result<(), String> {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).map_err(|e| format!("Can not open daemon port: {}", e))?;
+    let port = listener.local_addr().map_err(|e| format!("Can not get daemon port: {}", e))?.port();
+    let endpoint = DaemonEndpoint { port, token: get_random_token(), pid: std::process::id() };
+    let path = crate::paths::get_daemon_file(crate::get_profile().as_deref());
+    write_endpoint_file(&path, &endpoint)?;
+    write_debug(format!("Daemon listens on 127.0.0.1:{}, endpoint file {}", port, &path));
+
+    let (sender, receiver) = channel::<Job>();
+    let clients = Arc::new(AtomicUsize::new(0));
+    let accept_clients = clients.clone();
+    let token = endpoint.token.clone();
+    let log_file = crate::get_logfile_path();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let sender = sender.clone();
+            let clients = accept_clients.clone();
+            let token = token.clone();
+            let log_file = log_file.clone();
+            thread::spawn(move || {
+                clients.fetch_add(1, Ordering::SeqCst);
+                if let Err(err) = serve_client(stream, &token, sender) {
+                    write_debug_to(format!("Daemon client error: {}", err), &log_file, crate::is_debug_mode());
+                }
+                clients.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+
+    let idle_timeout = crate::get_config().daemon.idle_timeout;
+    let mut idle_since = Some(Instant::now());
+    loop {
+        match receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(job) => spawn_job(job),
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if clients.load(Ordering::SeqCst) > 0 {
+            idle_since = None;
+            continue;
+        }
+        let since = *idle_since.get_or_insert_with(Instant::now);
+        if idle_timeout > 0 && since.elapsed() > Duration::from_secs(idle_timeout) {
+            write_debug("No browsers are connected, stopping the daemon");
+            break;
+        }
+    }
+    remove_endpoint_file(&path);
+    Ok(())
+}
+
+/// Handles the message on its own thread and sends the response line to the client
+pub fn spawn_job((message, reply): Job) {
+    thread::spawn(move || {
+        let response = match crate::messages::handler(message) {
+            Ok(res) => serde_json::to_string(&res).unwrap_or_else(get_error_line),
+            Err(err) => get_error_line(err),
+        };
+        let _ = reply.send(response);
+    });
+}
+
+pub fn serve_client(stream: TcpStream, token: &str, sender: Sender<Job>) -> Result<(), String> {
+    let mut writer = stream.try_clone().map_err(|e| e.to_string())?;
+    let _ = stream.set_read_timeout(Some(AUTH_TIMEOUT));
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|e| format!("Can not read token: {}", e))?;
+    match serde_json::from_str::<DaemonAuth>(&line) {
+        Ok(auth) if is_same_token(auth.token.as_bytes(), token.as_bytes()) => {},
+        _ => {
+            let _ = writeln!(writer, "{}", get_error_line("Unauthorized"));
+            return Err(String::from("Client with an invalid token"));
+        }
+    }
+    let _ = reader.get_ref().set_read_timeout(None);
+    writeln!(writer, "{}", serde_json::json!({ "ok": true })).map_err(|e| e.to_string())?;
+    for line in reader.lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<SerdeValue>(&line) {
+            Ok(message) => {
+                let (reply_sender, reply) = channel();
+                sender.send((message, reply_sender)).map_err(|_| String::from("Daemon is stopping"))?;
+                reply.recv().map_err(|_| String::from("Daemon is stopping"))?
+            },
+            Err(err) => get_error_line(format!("Can not parse message: {}", err)),
+        };
+        writeln!(writer, "{}", response).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Forwards the native messages of the browser to the daemon of the profile, launching it if it doesn't run.
+/// Returns an error when the daemon is not reachable, the messages which are left should be handled in this process.
+pub fn run_shim(profile: Option<&str>) -> Result<(), String> {
+    let path = crate::paths::get_daemon_file(profile);
+    let mut connection = match DaemonConnection::open(&path) {
+        Ok(c) => c,
+        Err(_) => {
+            remove_endpoint_file(&path);
+            spawn_daemon()?;
+            wait_for_daemon(&path)?
+        }
+    };
+    write_debug(format!("Process {} forwards messages to the daemon", std::process::id()));
+    crate::watchdog::watch_parent();
+    loop {
+        let message = match read_input(io::stdin()) {
+            Ok(m) => m,
+            Err(NativeError::NoMoreInput) => return Ok(()),
+            Err(err) => return Err(format!("Can not read message: {}", err)),
+        };
+        let id = message.get("id").and_then(|id| id.as_str()).unwrap_or_default().to_string();
+        match connection.send(&message) {
+            Ok(response) => {
+                write_output(io::stdout(), &response).map_err(|e| format!("Can not write response: {}", e))?;
+            },
+            Err(err) => {
+                let res = get_internal_msg(id, 502, String::from("Companion daemon is not reachable"));
+                let _ = chrome_native_messaging::send_message(io::stdout(), &res);
+                return Err(err);
+            }
+        }
+    }
+}
+
+pub struct DaemonConnection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl DaemonConnection {
+    pub fn open(path: &str) -> Result<DaemonConnection, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("Can not read {}: {}", path, e))?;
+        let endpoint: DaemonEndpoint = serde_json::from_str(&content).map_err(|e| format!("Can not parse {}: {}", path, e))?;
+        let stream = TcpStream::connect(("127.0.0.1", endpoint.port)).map_err(|e| format!("Can not connect to the daemon: {}", e))?;
+        let mut connection = DaemonConnection {
+            writer: stream.try_clone().map_err(|e| e.to_string())?,
+            reader: BufReader::new(stream),
+        };
+        let reply = connection.send(&serde_json::json!({ "token": endpoint.token }))?;
+        if reply.get("ok").and_then(|ok| ok.as_bool()) != Some(true) {
+            return Err(format!("Daemon refused the connection: {}", reply));
+        }
+        Ok(connection)
+    }
+
+    pub fn send(&mut self, message: &SerdeValue) -> Result<SerdeValue, String> {
+        writeln!(self.writer, "{}", message).map_err(|e| format!("Can not send message to the daemon: {}", e))?;
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).map_err(|e| format!("Can not read response of the daemon: {}", e))? == 0 {
+            return Err(String::from("Daemon has closed the connection"));
+        }
+        serde_json::from_str(&line).map_err(|e| format!("Can not parse response of the daemon: {}", e))
+    }
+}
+
+/// Launches `alby --daemon` with the options of this process, detached from the browser.
+fn spawn_daemon() -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| format!("Can not get the executable path: {}", e))?;
+    // the rest are the manifest path and the extension ID passed by the browser
+    let args: Vec<String> = std::env::args().skip(1)
+        .filter(|a| a.starts_with('-') && !a.starts_with("--parent-window"))
+        .collect();
+    let mut command = Command::new(exe);
+    command.args(args).arg("--daemon")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // the browser stops the process group (the job on Windows) of the native messaging host
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const DETACHED_PROCESS: u32 = 0x00000008;
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x00000200;
+        const CREATE_BREAKAWAY_FROM_JOB: u32 = 0x01000000;
+        command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP | CREATE_BREAKAWAY_FROM_JOB);
+    }
+    command.spawn().map_err(|e| format!("Can not launch the daemon: {}", e))?;
+    write_debug("Daemon launched");
+    Ok(())
+}
+
+fn wait_for_daemon(path: &str) -> Result<DaemonConnection, String> {
+    let deadline = Instant::now() + DAEMON_START_TIMEOUT;
+    loop {
+        match DaemonConnection::open(path) {
+            Ok(connection) => return Ok(connection),
+            Err(err) if Instant::now() > deadline => return Err(err),
+            Err(_) => thread::sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+pub fn write_endpoint_file(path: &str, endpoint: &DaemonEndpoint) -> Result<(), String> {
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Can not create folder {}: {}", dir.to_string_lossy(), e))?;
+    }
+    let content = serde_json::to_string(endpoint).map_err(|e| format!("Can not serialize daemon endpoint: {}", e))?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .map_err(|e| format!("Can not write {}: {}", path, e))
+}
+
+/// Removes the endpoint file, unless it belongs to another running daemon.
+fn remove_endpoint_file(path: &str) {
+    let owner = fs::read_to_string(path).ok()
+        .and_then(|content| serde_json::from_str::<DaemonEndpoint>(&content).ok())
+        .map(|endpoint| endpoint.pid);
+    match owner {
+        Some(pid) if pid != std::process::id() && crate::is_pid_exists(pid) => {},
+        _ => {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn get_error_line<T: ToString>(err: T) -> String {
+    serde_json::json!({ "error": err.to_string() }).to_string()
+}
+
+/// Compares every byte whatever the first difference is, the time doesn't tell how much of the token is right
+pub fn is_same_token(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn get_random_token() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat(())
+        .map(|()| rng.sample(rand::distributions::Alphanumeric))
+        .map(char::from)
+        .take(32)
+        .collect()
+}
@@ -0,0 +1,331 @@
+use std::fs;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::messages::ReqMessage;
+use crate::routing::{is_onion_host, Route};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DiagnoseRequest {
+    /// Onion address to check through Tor
+    pub onion: Option<String>,
+    /// URL to check stage by stage: DNS, TCP, TLS, HTTP
+    pub url: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnoseReport {
+    pub version: String,
+    /// Instance profile, see `--profile`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    pub data_dir: Check,
+    pub log_file: Check,
+    pub tor: TorReport,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub onion: Option<Check>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<UrlReport>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TorReport {
+    /// `embedded`, `external` or `unavailable`
+    pub mode: String,
+    pub started: bool,
+    pub socks: Check,
+    #[cfg(feature = "embedded-tor")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bootstrap: Option<crate::tor::BootstrapStatus>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UrlReport {
+    pub url: String,
+    pub route: Option<Route>,
+    pub dns: Check,
+    pub tcp: Check,
+    pub tls: Option<Check>,
+    pub http: Check,
+    /// Seconds between the local clock and the `Date` of the response
+    pub clock_skew: Option<i64>,
+}
+
+/// Result of one stage, `skipped` when a previous stage has failed
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub skipped: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u128>,
+}
+
+impl Check {
+    pub fn ok<T: ToString>(detail: T) -> Check {
+        Check { ok: true, detail: Some(detail.to_string()), ..Default::default() }
+    }
+
+    pub fn failed<T: ToString>(error: T) -> Check {
+        Check { ok: false, error: Some(error.to_string()), ..Default::default() }
+    }
+
+    pub fn skipped() -> Check {
+        Check { skipped: true, ..Default::default() }
+    }
+
+    fn timed(mut self, started: Instant) -> Check {
+        self.duration_ms = Some(started.elapsed().as_millis());
+        self
+    }
+}
+
+pub fn diagnose(req: &DiagnoseRequest) -> DiagnoseReport {
+    DiagnoseReport {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        profile: crate::get_profile(),
+        data_dir: check_dir_writable(&crate::get_tor_dir_path()),
+        log_file: check_log_file(&crate::get_logfile_path()),
+        tor: get_tor_report(),
+        onion: req.onion.as_ref().map(|onion| check_onion(onion)),
+        url: req.url.as_ref().map(|url| check_url(url)),
+    }
+}
+
+/// Checks the permissions of the folder, or of its nearest existing parent when it is created later: creates nothing.
+pub fn check_dir_writable(dir: &str) -> Check {
+    let existing = Path::new(dir).ancestors().find(|p| p.exists()).unwrap_or_else(|| Path::new("."));
+    if !existing.is_dir() {
+        return Check::failed(format!("{} is not a folder", existing.to_string_lossy()));
+    }
+    if !is_writable(existing) {
+        return Check::failed(format!("{} is not writable", existing.to_string_lossy()));
+    }
+    Check::ok(dir)
+}
+
+/// As `check_dir_writable`, the file is not created either.
+pub fn check_log_file(path: &str) -> Check {
+    match fs::metadata(path) {
+        Ok(meta) if meta.is_dir() => Check::failed(format!("{} is a folder", path)),
+        Ok(_) if is_writable(Path::new(path)) => Check::ok(path),
+        Ok(_) => Check::failed(format!("{} is not writable", path)),
+        Err(_) => {
+            let dir = Path::new(path).parent().map(|d| d.to_string_lossy().to_string()).unwrap_or_default();
+            match check_dir_writable(&dir) {
+                Check { ok: true, .. } => Check::ok(path),
+                check => check,
+            }
+        },
+    }
+}
+
+#[cfg(unix)]
+fn is_writable(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_writable(path: &Path) -> bool {
+    fs::metadata(path).map(|m| !m.permissions().readonly()).unwrap_or(false)
+}
+
+fn get_tor_report() -> TorReport {
+    if let Some(proxy) = crate::proxy::get_external_proxy() {
+        let addr = format!("{}:{}", &proxy.host, proxy.port);
+        return TorReport {
+            mode: String::from("external"),
+            started: true,
+            socks: match crate::proxy::is_socks5_proxy(&proxy) {
+                true => Check::ok(addr),
+                false => {
+                    crate::proxy::forget_system_tor();
+                    Check::failed(format!("No SOCKS5 proxy on {}", addr))
+                },
+            },
+            #[cfg(feature = "embedded-tor")]
+            bootstrap: None,
+        };
+    }
+    get_embedded_tor_report()
+}
+
+#[cfg(feature = "embedded-tor")]
+fn get_embedded_tor_report() -> TorReport {
+    let started = crate::is_tor_started();
+    let socks = match started {
+        false => Check::skipped(),
+        true => check_embedded_socks(),
+    };
+    TorReport {
+        mode: String::from("embedded"),
+        started,
+        socks,
+        bootstrap: match started {
+            true => Some(crate::tor::get_bootstrap_status()),
+            false => None,
+        },
+    }
+}
+
+#[cfg(not(feature = "embedded-tor"))]
+fn get_embedded_tor_report() -> TorReport {
+    TorReport {
+        mode: String::from("unavailable"),
+        started: false,
+        socks: Check::failed("Tor not available in this build"),
+    }
+}
+
+#[cfg(feature = "embedded-tor")]
+fn check_embedded_socks() -> Check {
+    #[cfg(unix)]
+    if let Some(socket) = crate::get_tor_socks_socket() {
+        return match std::os::unix::net::UnixStream::connect(&socket) {
+            Ok(_) => Check::ok(socket),
+            Err(err) => Check::failed(format!("SOCKS socket {} is not bound: {}", socket, err)),
+        };
+    }
+    let addr = format!("127.0.0.1:{}", crate::get_tor_port());
+    match check_tcp(&addr) {
+        Ok(_) => Check::ok(addr),
+        Err(err) => Check::failed(format!("SOCKS port {} is not bound: {}", addr, err)),
+    }
+}
+
+#[cfg(feature = "embedded-tor")]
+fn check_tcp(addr: &str) -> Result<(), String> {
+    let addr: SocketAddr = addr.parse().map_err(|e| format!("{}", e))?;
+    TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// Doesn't launch the embedded Tor: the check would wait for the bootstrap and keep Tor running.
+fn check_onion(onion: &str) -> Check {
+    #[cfg(feature = "embedded-tor")]
+    if crate::proxy::get_external_proxy().is_none() && !crate::is_tor_started() {
+        return Check::failed("Tor not started");
+    }
+    let url = match onion.contains("://") {
+        true => onion.to_string(),
+        false => format!("http://{}", onion),
+    };
+    let started = Instant::now();
+    let msg = ReqMessage {
+        id: String::from("diagnose"),
+        url,
+        route: Some(Route::Tor),
+        ..Default::default()
+    };
+    match crate::requests::get_response(msg) {
+        // internal status messages (e.g. Tor failed to start) are not responses of the onion service
+        Ok(res) if res.headers.contains_key("X-Alby-Internal") => Check::failed(res.body).timed(started),
+        Ok(res) => Check::ok(format!("HTTP {}", res.status)).timed(started),
+        Err(err) => Check::failed(format!("{:?}", err)).timed(started),
+    }
+}
+
+fn check_url(url_str: &str) -> UrlReport {
+    let mut report = UrlReport {
+        url: url_str.to_string(),
+        route: None,
+        dns: Check::skipped(),
+        tcp: Check::skipped(),
+        tls: None,
+        http: Check::skipped(),
+        clock_skew: None,
+    };
+    let url = match reqwest::Url::parse(url_str) {
+        Ok(u) => u,
+        Err(err) => {
+            report.dns = Check::failed(format!("Can not parse URL: {}", err));
+            return report;
+        }
+    };
+    let host = url.host_str().unwrap_or_default().to_string();
+    let port = url.port_or_known_default().unwrap_or(443);
+    let is_https = url.scheme() == "https";
+    report.route = crate::routing::get_route(&url, None, &crate::get_config().routing).ok();
+    if is_onion_host(&host) || report.route == Some(Route::Tor) {
+        // DNS and TCP are done by Tor
+        report.http = check_onion(url_str);
+        return report;
+    }
+
+    let started = Instant::now();
+    let addrs: Vec<SocketAddr> = match (host.trim_start_matches('[').trim_end_matches(']'), port).to_socket_addrs() {
+        Ok(a) => a.collect(),
+        Err(err) => {
+            report.dns = Check::failed(err).timed(started);
+            return report;
+        }
+    };
+    let addr_list: Vec<String> = addrs.iter().map(|a| a.ip().to_string()).collect();
+    report.dns = Check::ok(addr_list.join(", ")).timed(started);
+
+    let started = Instant::now();
+    match addrs.iter().find_map(|a| TcpStream::connect_timeout(a, CONNECT_TIMEOUT).ok().map(|_| a)) {
+        Some(addr) => report.tcp = Check::ok(addr).timed(started),
+        None => {
+            report.tcp = Check::failed(format!("Can not connect to port {}", port)).timed(started);
+            return report;
+        }
+    }
+
+    let client = |accept_invalid_certs: bool| reqwest::blocking::Client::builder()
+        .timeout(CONNECT_TIMEOUT)
+        .danger_accept_invalid_certs(accept_invalid_certs)
+        .build();
+    let started = Instant::now();
+    let response = client(false).and_then(|c| c.get(url.clone()).send());
+    let response = match response {
+        Ok(res) => {
+            if is_https {
+                report.tls = Some(Check::ok("trusted certificate").timed(started));
+            }
+            res
+        },
+        Err(err) if is_https => {
+            // a request accepting any certificate tells TLS problems from the rest
+            match client(true).and_then(|c| c.get(url.clone()).send()) {
+                Ok(res) => {
+                    report.tls = Some(Check::failed(format!("Certificate is not trusted (self-signed?): {}", err)).timed(started));
+                    res
+                },
+                Err(_) => {
+                    report.tls = Some(Check::failed(err).timed(started));
+                    return report;
+                }
+            }
+        },
+        Err(err) => {
+            report.http = Check::failed(err).timed(started);
+            return report;
+        }
+    };
+    report.clock_skew = response.headers().get(reqwest::header::DATE)
+        .and_then(|d| d.to_str().ok())
+        .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+        .map(|d| chrono::Utc::now().timestamp() - d.timestamp());
+    report.http = Check::ok(format!("HTTP {}", response.status())).timed(started);
+    report
+}
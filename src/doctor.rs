@@ -0,0 +1,212 @@
+use std::fs;
+use std::path::Path;
+
+use fs2::FileExt;
+use serde::Serialize;
+use serde_json::Value as SerdeValue;
+
+use crate::cli::DoctorOptions;
+use crate::diagnose::{Check, check_dir_writable, check_log_file};
+use crate::install::{Browser, BROWSERS, get_home_dir, get_manifest, HOST_NAME, Manifest};
+use crate::paths::get_profile_path;
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DoctorReport {
+    pub version: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    pub manifests: Vec<ManifestCheck>,
+    pub log_file: Check,
+    pub tor_dir: Check,
+    /// Whether an instance holds the lock of the profile, or of any slot without a profile
+    pub instance: Check,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestCheck {
+    pub browser: String,
+    pub path: String,
+    #[serde(flatten)]
+    pub check: Check,
+}
+
+/// Prints the report, fails if a check has failed.
+pub fn doctor(opts: &DoctorOptions) -> Result<(), String> {
+    let report = get_report();
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&report).map_err(|e| format!("Can not serialize report: {}", e))?);
+    } else {
+        print_report(&report);
+    }
+    match report.ok {
+        true => Ok(()),
+        false => Err(String::from("Some checks have failed")),
+    }
+}
+
+pub fn get_report() -> DoctorReport {
+    let manifests = match get_home_dir() {
+        Ok(home) => {
+            let config = crate::get_config();
+            BROWSERS.iter().map(|b| {
+                let expected = get_manifest(*b, Path::new(""), &config.allowed_extensions, &config.allowed_origins);
+                check_browser(*b, &home, &expected)
+            }).collect()
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            vec![]
+        }
+    };
+    let log_file = check_log_file(&crate::get_logfile_path());
+    let tor_dir = check_dir_writable(&crate::get_tor_dir_path());
+    let instance = check_instances(&get_lock_file_paths());
+    let ok = log_file.ok && tor_dir.ok && instance.ok && manifests.iter().all(|m| m.check.ok || m.check.skipped);
+    DoctorReport {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        ok,
+        profile: crate::get_profile(),
+        manifests,
+        log_file,
+        tor_dir,
+        instance,
+    }
+}
+
+fn print_report(report: &DoctorReport) {
+    println!("alby {}", &report.version);
+    if let Some(profile) = &report.profile {
+        println!("Profile: {}", profile);
+    }
+    for manifest in &report.manifests {
+        print_check(&format!("{} manifest {}", &manifest.browser, &manifest.path), &manifest.check);
+    }
+    print_check("Log file", &report.log_file);
+    print_check("Tor folder", &report.tor_dir);
+    print_check("Instance", &report.instance);
+}
+
+fn print_check(name: &str, check: &Check) {
+    let state = match (check.ok, check.skipped) {
+        (true, _) => "[ OK ]",
+        (false, true) => "[SKIP]",
+        (false, false) => "[FAIL]",
+    };
+    let text = check.error.as_ref().or(check.detail.as_ref());
+    match text {
+        Some(text) => println!("{} {}: {}", state, name, text),
+        None => println!("{} {}", state, name),
+    }
+}
+
+/// Browsers which are not found are skipped unless they have a manifest.
+fn check_browser(browser: Browser, home: &Path, expected: &Manifest) -> ManifestCheck {
+    let path = browser.get_manifest_path(home);
+    let check = match fs::read_to_string(&path) {
+        Ok(content) => match check_manifest(&content, expected) {
+            Ok(detail) => Check::ok(detail),
+            Err(err) => Check::failed(err),
+        },
+        Err(_) if !browser.is_installed(home) => Check { skipped: true, detail: Some(String::from("browser not found")), ..Default::default() },
+        Err(err) => Check::failed(format!("{}, run `alby install`", err)),
+    };
+    ManifestCheck {
+        browser: browser.to_string(),
+        path: path.to_string_lossy().to_string(),
+        check,
+    }
+}
+
+/// Validates the manifest the way the browser does, returns the path of the executable.
+/// The expected manifest has the allowed extension IDs or origins, its path is not checked.
+pub fn check_manifest(content: &str, expected: &Manifest) -> Result<String, String> {
+    let manifest: SerdeValue = serde_json::from_str(content).map_err(|e| format!("invalid JSON: {}", e))?;
+    if manifest["name"] != HOST_NAME {
+        return Err(format!("name is not {}", HOST_NAME));
+    }
+    if manifest["type"] != "stdio" {
+        return Err(String::from("type is not stdio"));
+    }
+    let exe = manifest["path"].as_str().ok_or_else(|| String::from("path is missing"))?;
+    // Firefox resolves relative paths, Chrome does too on Linux, but they break when the manifest is copied
+    if !Path::new(exe).is_absolute() {
+        return Err(format!("path {} is not absolute", exe));
+    }
+    if !is_executable(Path::new(exe)) {
+        return Err(format!("{} does not exist or is not executable", exe));
+    }
+    let expected = [("allowed_extensions", &expected.allowed_extensions), ("allowed_origins", &expected.allowed_origins)];
+    for (key, values) in expected {
+        let values = match values {
+            Some(values) => values,
+            None => continue,
+        };
+        let allowed = manifest[key].as_array().ok_or_else(|| format!("{} is missing", key))?;
+        if let Some(missing) = values.iter().find(|v| !allowed.iter().any(|a| a == *v)) {
+            return Err(format!("{} does not contain {}", key, missing));
+        }
+    }
+    Ok(exe.to_string())
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    match fs::metadata(path) {
+        Ok(meta) => meta.is_file() && meta.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// The lock file of the profile, or of every slot an instance without a profile takes: the first one has no suffix.
+pub fn get_lock_file_paths() -> Vec<String> {
+    let path = crate::get_lock_file_path();
+    if crate::get_profile().is_some() {
+        return vec![path];
+    }
+    let log_file = crate::get_logfile_path();
+    let slots = (2..=crate::MAX_INSTANCES).map(|slot| format!("{}.process", get_profile_path(&log_file, &slot.to_string())));
+    std::iter::once(path).chain(slots).collect()
+}
+
+/// Lists the running instances, fails if a lock file can not be checked.
+fn check_instances(paths: &[String]) -> Check {
+    let mut running = vec![];
+    for path in paths {
+        match check_instance(path) {
+            Check { ok: true, detail: Some(detail), .. } if detail != "not running" => running.push(detail),
+            Check { ok: true, .. } => {},
+            check => return check,
+        }
+    }
+    match running.is_empty() {
+        true => Check::ok("not running"),
+        false => Check::ok(running.join("; ")),
+    }
+}
+
+/// Locks and releases the lock file right away, it doesn't take the slot of the instance.
+/// A missing lock file is not created.
+pub fn check_instance(path: &str) -> Check {
+    let file = match fs::OpenOptions::new().read(true).write(true).open(path) {
+        Ok(f) => f,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Check::ok("not running"),
+        Err(err) => return Check::failed(format!("{}: {}", path, err)),
+    };
+    match file.try_lock_exclusive() {
+        Ok(_) => {
+            let _ = file.unlock();
+            Check::ok("not running")
+        },
+        Err(_) => Check::ok(format!("running, {}", crate::read_lock_owner(path))),
+    }
+}
@@ -0,0 +1,106 @@
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+
+use serde_json::Value as SerdeValue;
+
+// Also compiled into the integration tests, so it only depends on std and serde_json.
+
+/// Larger frames are refused, the length of garbage input would be allocated otherwise
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Native messaging frame: the length of the JSON as u32 in native byte order, then the JSON
+pub fn encode(value: &SerdeValue) -> Result<Vec<u8>, String> {
+    let json = serde_json::to_vec(value).map_err(|e| format!("Can not serialize message: {}", e))?;
+    if json.len() > MAX_FRAME_SIZE {
+        return Err(format!("Message of {} bytes is too large", json.len()));
+    }
+    let mut frame = (json.len() as u32).to_ne_bytes().to_vec();
+    frame.extend(json);
+    Ok(frame)
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, value: &SerdeValue) -> Result<(), String> {
+    writer.write_all(&encode(value)?).and_then(|_| writer.flush()).map_err(|e| format!("Can not write frame: {}", e))
+}
+
+/// None at the end of input between frames
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<SerdeValue>, String> {
+    let mut length = [0u8; 4];
+    let mut read = 0;
+    while read < length.len() {
+        match reader.read(&mut length[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(String::from("Input ends inside the length of a frame")),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(format!("Can not read frame: {}", err)),
+        }
+    }
+    let length = u32::from_ne_bytes(length) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(format!("Frame of {} bytes is too large", length));
+    }
+    let mut json = vec![0u8; length];
+    reader.read_exact(&mut json).map_err(|e| format!("Can not read frame of {} bytes: {}", length, e))?;
+    serde_json::from_slice(&json).map(Some).map_err(|e| format!("Invalid JSON in frame: {}", e))
+}
+
+pub fn decode_all(mut bytes: &[u8]) -> Result<Vec<SerdeValue>, String> {
+    let mut values = vec![];
+    while let Some(value) = read_frame(&mut bytes)? {
+        values.push(value);
+    }
+    Ok(values)
+}
+
+/// Messages of a captured session: native messaging frames, JSON lines or a JSON array
+pub fn parse_session(bytes: &[u8]) -> Result<Vec<SerdeValue>, String> {
+    if let Ok(values) = decode_all(bytes) {
+        if !values.is_empty() {
+            return Ok(values);
+        }
+    }
+    let mut values = vec![];
+    for value in serde_json::Deserializer::from_slice(bytes).into_iter::<SerdeValue>() {
+        match value.map_err(|e| format!("Session is neither native messaging frames nor JSON: {}", e))? {
+            SerdeValue::Array(items) => values.extend(items),
+            value => values.push(value),
+        }
+    }
+    Ok(values)
+}
+
+/// Native messaging host started the way the browser does it, with piped stdin and stdout.
+pub struct Host {
+    child: Child,
+    /// Taken to write from another thread, dropped to close the input
+    pub input: Option<ChildStdin>,
+    output: ChildStdout,
+}
+
+impl Host {
+    pub fn spawn(program: &Path, args: &[String]) -> Result<Host, String> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| format!("Can not start {}: {}", program.to_string_lossy(), e))?;
+        let input = child.stdin.take();
+        let output = child.stdout.take().ok_or_else(|| String::from("Output of the host is not piped"))?;
+        Ok(Host { child, input, output })
+    }
+
+    /// None when the host has closed its output
+    pub fn receive(&mut self) -> Result<Option<SerdeValue>, String> {
+        read_frame(&mut self.output)
+    }
+
+    /// Closes the input like the browser does, the host exits then.
+    pub fn close(mut self) -> Result<ExitStatus, String> {
+        self.input = None;
+        self.child.wait().map_err(|e| format!("Can not wait for the host: {}", e))
+    }
+}
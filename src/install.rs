@@ -0,0 +1,223 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::cli::{InstallOptions, UninstallOptions};
+
+/// Name of the native messaging host, the extension connects to it by this name
+pub const HOST_NAME: &str = "alby";
+pub const DEFAULT_EXTENSION_ID: &str = "extension@getalby.com";
+pub const DEFAULT_ORIGIN: &str = "chrome-extension://iokeahhehimjnekafflcihljlcjccdbe/";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Browser {
+    Firefox,
+    Chrome,
+    Chromium,
+    Brave,
+    Vivaldi,
+    Edge,
+}
+
+pub const BROWSERS: [Browser; 6] = [Browser::Firefox, Browser::Chrome, Browser::Chromium, Browser::Brave, Browser::Vivaldi, Browser::Edge];
+
+impl Browser {
+    pub fn parse(name: &str) -> Result<Browser, String> {
+        BROWSERS.iter().find(|b| b.get_name() == name.to_lowercase()).copied()
+            .ok_or_else(|| format!("expected one of {}", BROWSERS.map(|b| b.get_name()).join(", ")))
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Browser::Firefox => "firefox",
+            Browser::Chrome => "chrome",
+            Browser::Chromium => "chromium",
+            Browser::Brave => "brave",
+            Browser::Vivaldi => "vivaldi",
+            Browser::Edge => "edge",
+        }
+    }
+
+    /// Per-user folder of the browser on Linux, relative to the home folder
+    fn get_config_dir(&self) -> &'static str {
+        match self {
+            Browser::Firefox => ".mozilla",
+            Browser::Chrome => ".config/google-chrome",
+            Browser::Chromium => ".config/chromium",
+            Browser::Brave => ".config/BraveSoftware/Brave-Browser",
+            Browser::Vivaldi => ".config/vivaldi",
+            Browser::Edge => ".config/microsoft-edge",
+        }
+    }
+
+    pub fn is_installed(&self, home: &Path) -> bool {
+        home.join(self.get_config_dir()).is_dir()
+    }
+
+    pub fn get_manifest_path(&self, home: &Path) -> PathBuf {
+        let dir = match self {
+            Browser::Firefox => home.join(".mozilla").join("native-messaging-hosts"),
+            _ => home.join(self.get_config_dir()).join("NativeMessagingHosts"),
+        };
+        dir.join(format!("{}.json", HOST_NAME))
+    }
+}
+
+impl Display for Browser {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.get_name())
+    }
+}
+
+/// Firefox allows extensions by ID, the Chromium browsers by origin.
+#[derive(Serialize, Debug)]
+pub struct Manifest {
+    pub name: String,
+    pub description: String,
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_extensions: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_origins: Option<Vec<String>>,
+}
+
+pub fn get_manifest(browser: Browser, exe: &Path, extension_ids: &[String], origins: &[String]) -> Manifest {
+    let (allowed_extensions, allowed_origins) = match browser {
+        Browser::Firefox => (Some(get_or_default(extension_ids, DEFAULT_EXTENSION_ID)), None),
+        _ => (None, Some(get_or_default(origins, DEFAULT_ORIGIN).iter().map(|o| get_origin(o)).collect())),
+    };
+    Manifest {
+        name: HOST_NAME.to_string(),
+        description: String::from("Alby native messaging to connect to nodes behind Tor"),
+        path: exe.to_string_lossy().to_string(),
+        kind: String::from("stdio"),
+        allowed_extensions,
+        allowed_origins,
+    }
+}
+
+/// `iokeahhehimjnekafflcihljlcjccdbe` => `chrome-extension://iokeahhehimjnekafflcihljlcjccdbe/`, Chrome requires the trailing slash
+pub fn get_origin(val: &str) -> String {
+    let origin = match val.starts_with("chrome-extension://") {
+        true => val.to_string(),
+        false => format!("chrome-extension://{}", val),
+    };
+    match origin.ends_with('/') {
+        true => origin,
+        false => format!("{}/", origin),
+    }
+}
+
+fn get_or_default(values: &[String], default: &str) -> Vec<String> {
+    match values.is_empty() {
+        true => vec![default.to_string()],
+        false => values.to_vec(),
+    }
+}
+
+/// Writes the manifests pointing to the running executable, the IDs and origins of the config file by default.
+pub fn install(opts: &InstallOptions) -> Result<(), String> {
+    let home = get_home_dir()?;
+    let exe = std::env::current_exe().map_err(|e| format!("Can not get the path of the executable: {}", e))?;
+    let config = crate::get_config();
+    let mut opts = opts.clone();
+    if opts.extension_ids.is_empty() {
+        opts.extension_ids = config.allowed_extensions;
+    }
+    if opts.origins.is_empty() {
+        opts.origins = config.allowed_origins;
+    }
+    for line in install_manifests(&home, &exe, &opts)? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+pub fn uninstall(opts: &UninstallOptions) -> Result<(), String> {
+    let home = get_home_dir()?;
+    for line in uninstall_manifests(&home, opts)? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Returns what was changed or, on a dry run, what would change.
+pub fn install_manifests(home: &Path, exe: &Path, opts: &InstallOptions) -> Result<Vec<String>, String> {
+    if !exe.is_absolute() {
+        return Err(format!("Path of the executable is not absolute: {}", exe.to_string_lossy()));
+    }
+    let mut lines = vec![];
+    for browser in get_browsers(home, &opts.browsers)? {
+        let path = browser.get_manifest_path(home);
+        let manifest = get_manifest(browser, exe, &opts.extension_ids, &opts.origins);
+        let content = serde_json::to_string_pretty(&manifest).map_err(|e| format!("Can not serialize manifest: {}", e))?;
+        let action = match fs::read_to_string(&path) {
+            Ok(current) if current == content => {
+                lines.push(format!("{}: {} is up to date", browser, path.to_string_lossy()));
+                continue;
+            },
+            Ok(_) => "update",
+            Err(_) => "create",
+        };
+        if opts.dry_run {
+            lines.push(format!("{}: would {} {}\n{}", browser, action, path.to_string_lossy(), &content));
+            continue;
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("Can not create folder {}: {}", dir.to_string_lossy(), e))?;
+        }
+        fs::write(&path, &content).map_err(|e| format!("Can not write {}: {}", path.to_string_lossy(), e))?;
+        lines.push(format!("{}: {}d {}", browser, action, path.to_string_lossy()));
+    }
+    Ok(lines)
+}
+
+pub fn uninstall_manifests(home: &Path, opts: &UninstallOptions) -> Result<Vec<String>, String> {
+    let browsers = match opts.browsers.is_empty() {
+        true => BROWSERS.to_vec(),
+        false => opts.browsers.clone(),
+    };
+    let mut lines = vec![];
+    for browser in browsers {
+        let path = browser.get_manifest_path(home);
+        if !path.exists() {
+            continue;
+        }
+        if opts.dry_run {
+            lines.push(format!("{}: would remove {}", browser, path.to_string_lossy()));
+            continue;
+        }
+        fs::remove_file(&path).map_err(|e| format!("Can not remove {}: {}", path.to_string_lossy(), e))?;
+        lines.push(format!("{}: removed {}", browser, path.to_string_lossy()));
+    }
+    if lines.is_empty() {
+        lines.push(String::from("No manifest is installed"));
+    }
+    Ok(lines)
+}
+
+/// The given browsers, otherwise the ones which have a folder in the home folder
+fn get_browsers(home: &Path, browsers: &[Browser]) -> Result<Vec<Browser>, String> {
+    if !browsers.is_empty() {
+        return Ok(browsers.to_vec());
+    }
+    let installed: Vec<Browser> = BROWSERS.iter().filter(|b| b.is_installed(home)).copied().collect();
+    match installed.is_empty() {
+        true => Err(String::from("No supported browser found, select one with --browser")),
+        false => Ok(installed),
+    }
+}
+
+pub fn get_home_dir() -> Result<PathBuf, String> {
+    if !cfg!(target_os = "linux") {
+        return Err(String::from("Installing the manifests is only supported on Linux, see the README for the other systems"));
+    }
+    match std::env::var_os("HOME") {
+        Some(home) if !home.is_empty() => Ok(PathBuf::from(home)),
+        _ => Err(String::from("HOME is not set")),
+    }
+}
@@ -1,66 +1,305 @@
 // extern crate chrono;
 
 use std::{fs, thread};
-use std::borrow::Borrow;
-use std::cell::RefCell;
 use std::fmt::Display;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
 use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "embedded-tor")]
+use std::sync::atomic::AtomicU16;
 use std::time::SystemTime;
 
 use chrome_native_messaging::event_loop;
 use chrono::DateTime;
+#[cfg(feature = "embedded-tor")]
 use rand::{Rng, thread_rng};
 #[cfg(not(windows))]
 use signal_hook::consts::TERM_SIGNALS;
 #[cfg(not(windows))]
 use signal_hook::iterator::Signals;
-use sysinfo::{PidExt, System, SystemExt};
+use fs2::FileExt;
+use sysinfo::{PidExt, ProcessExt, ProcessRefreshKind, Signal, System, SystemExt};
 
 #[cfg(test)]
 mod test;
+#[cfg(test)]
+mod test_servers;
 
 mod messages;
+#[cfg(feature = "embedded-tor")]
 mod tor;
+#[cfg(feature = "embedded-tor")]
+mod control;
+#[cfg(feature = "embedded-tor")]
+mod onion_auth;
+#[cfg(feature = "embedded-tor")]
+mod onion_service;
+#[cfg(all(unix, feature = "embedded-tor"))]
+mod socks_relay;
 mod requests;
 mod cli;
+mod config;
+mod proxy;
+mod paths;
+mod routing;
+mod diagnose;
+mod daemon;
+mod shutdown;
+mod watchdog;
+mod install;
+mod doctor;
+mod framing;
+mod replay;
+
+/// Shared by all threads: the daemon handles the messages of the browsers at the same time
+#[cfg(feature = "embedded-tor")]
+static TOR_USERNAME: Setting<String> = Setting::new(|| format!("u{}", get_random_string()));
+#[cfg(feature = "embedded-tor")]
+static TOR_PASSWORD: Setting<String> = Setting::new(get_random_string);
+static LOG_FILE: Setting<String> = Setting::new(|| format!("{}", std::env::temp_dir().join("alby.log").to_string_lossy()));
+static TOR_DIR: Setting<String> = Setting::new(paths::get_default_tor_dir);
+static CONFIG_FILE: Setting<String> = Setting::new(paths::get_default_config_file);
+static CONFIG: Setting<config::Config> = Setting::new(Default::default);
+static TOR_STARTED: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "embedded-tor")]
+static TOR_READY: AtomicBool = AtomicBool::new(false);
+static PROFILE: Setting<Option<String>> = Setting::new(|| None);
+
+/// Process-wide value, the default is computed on the first use
+struct Setting<T> {
+    value: Mutex<Option<T>>,
+    default: fn() -> T,
+}
 
-thread_local!(
-    static TOR_PORT: u16 = get_random_port();
-    static TOR_USERNAME: String = format!("u{}", get_random_string());
-    static TOR_PASSWORD: String = get_random_string();
-    static LOG_FILE: RefCell<String> = RefCell::new(format!("{}", std::env::temp_dir().join("alby.log").to_string_lossy()));
-    static TOR_DIR: RefCell<String> = RefCell::new(format!("{}",std::env::temp_dir().join("alby-tor").to_string_lossy()));
-    static TOR_STARTED: RefCell<bool> = RefCell::new(false);
-    static TOR_READY: RefCell<bool> = RefCell::new(false);
-    static DEBUG_MODE: RefCell<bool> = RefCell::new(false);
-);
+impl<T: Clone> Setting<T> {
+    const fn new(default: fn() -> T) -> Setting<T> {
+        Setting { value: Mutex::new(None), default }
+    }
+
+    fn get(&self) -> T {
+        let mut value = self.value.lock().unwrap_or_else(|e| e.into_inner());
+        value.get_or_insert_with(self.default).clone()
+    }
+
+    fn set(&self, val: T) {
+        *self.value.lock().unwrap_or_else(|e| e.into_inner()) = Some(val);
+    }
+
+    #[cfg(test)]
+    fn reset(&self) {
+        *self.value.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+/// The tests share the settings of the process, each one starts with the defaults
+#[cfg(test)]
+fn reset_settings() {
+    LOG_FILE.reset();
+    TOR_DIR.reset();
+    CONFIG_FILE.reset();
+    CONFIG.reset();
+    PROFILE.reset();
+    set_debug_mode(false);
+}
+
+/// Companions started without a profile take the first free slot: `alby.log`, `alby-2.log`, ...
+const MAX_INSTANCES: u8 = 8;
+/// How long the running instance gets to shut down on a takeover, longer than its shutdown timeout
+const TAKEOVER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
 
 fn main() {
     let opts = cli::get_cli_options(cli::get_args_from_cli());
-    if let Some(val) = opts.log_file {
-        LOG_FILE.with(|v| { *v.borrow_mut() = val.to_string() });
+    apply_cli_options(&opts);
+    match opts.command.clone() {
+        cli::Command::Serve => serve(opts),
+        cli::Command::Install(args) => exit_on_error(install::install(&args)),
+        cli::Command::Uninstall(args) => exit_on_error(install::uninstall(&args)),
+        cli::Command::Doctor(args) => {
+            if let Some(profile) = get_cli_profile(&opts) {
+                set_profile(&get_logfile_path(), &get_tor_dir_path(), profile);
+            }
+            exit_on_error(doctor::doctor(&args))
+        },
+        cli::Command::Request(args) => request(opts, args),
+        cli::Command::Replay(args) => exit_on_error(replay::replay(&args)),
+    }
+}
+
+/// Result of a command which doesn't serve the browser
+fn exit_on_error(result: Result<(), String>) {
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
+
+/// Options from the command line and the environment take precedence over the config file.
+fn apply_cli_options(opts: &cli::CliOptions) {
+    match &opts.config_file {
+        Some(val) => CONFIG_FILE.set(val.to_string()),
+        None => {
+            paths::migrate_config_file(&paths::get_legacy_config_file(), Path::new(&get_config_file_path()));
+        },
+    }
+    let mut config = match config::load_config(&get_config_file_path()) {
+        Ok(c) => c,
+        Err(err) => {
+            eprintln!("{}", err);
+            Default::default()
+        }
+    };
+    if let Some(val) = opts.log_file.as_ref().or(config.log.file.as_ref()) {
+        LOG_FILE.set(val.to_string());
+    }
+    if let Some(val) = opts.tor_dir.as_ref().or(config.tor_dir.as_ref()) {
+        TOR_DIR.set(val.to_string());
+    }
+    let log_level = match opts.debug_mode {
+        true => config::LogLevel::Debug,
+        false => opts.log_level.unwrap_or(config.log.level),
+    };
+    set_debug_mode(log_level == config::LogLevel::Debug);
+    config.log.level = log_level;
+    if opts.use_bridges {
+        config.tor.use_bridges = true;
+    }
+    if !opts.bridges.is_empty() {
+        config.tor.bridges = opts.bridges.clone();
+    }
+    config.tor.transport_plugins.extend(opts.transport_plugins.clone());
+    if let Some(proxy) = &opts.proxy {
+        config.tor.proxy = Some(proxy.clone());
+    }
+    if opts.use_system_tor {
+        config.tor.use_system_tor = true;
     }
-    if let Some(val) = opts.tor_dir {
-        TOR_DIR.with(|v| { *v.borrow_mut() = val.to_string() });
+    if let Some(port) = opts.socks_port {
+        config.tor.socks_port = Some(port);
     }
-    if opts.debug_mode {
-        set_debug_mode(true);
+    if let Some(route) = opts.default_route {
+        config.routing.default_route = route;
     }
+    set_config(config);
+}
 
-    let lock = create_lock_file();
-    if lock.is_none() {
-        eprintln!("Only one instance allowed!");
+/// `--profile` or `ALBY_PROFILE`, exits if it is invalid
+fn get_cli_profile(opts: &cli::CliOptions) -> Option<String> {
+    let profile = opts.profile.clone().or_else(|| std::env::var("ALBY_PROFILE").ok().filter(|p| !p.is_empty()));
+    if let Some(profile) = &profile {
+        if !paths::is_valid_profile(profile) {
+            eprintln!("Invalid profile {}, only letters, digits, - and _ are allowed", profile);
+            std::process::exit(1);
+        }
+    }
+    profile
+}
+
+/// Native messaging with the browser, directly or through the daemon.
+fn serve(opts: cli::CliOptions) {
+    let custom_tor_dir = opts.tor_dir.is_some() || get_config().tor_dir.is_some();
+    let profile = get_cli_profile(&opts);
+    if get_config().daemon.enabled && !opts.daemon {
+        let shim = with_profile_log_file(profile.as_deref(), || {
+            daemon::run_shim(profile.as_deref())
+                .map_err(|err| write_debug(format!("Companion daemon is not available, messages are handled by this process: {}", err)))
+        });
+        if shim.is_ok() {
+            std::process::exit(0);
+        }
+    }
+
+    // browsers look for the daemon of the profile, so it doesn't take another slot
+    let mut lock = lock_instance(profile.clone(), if opts.daemon { 1 } else { MAX_INSTANCES });
+    if lock.is_none() && opts.daemon {
+        eprintln!("Companion daemon is already running!");
         std::process::exit(1);
     }
+    if lock.is_none() && (opts.takeover || get_config().instance.takeover) {
+        lock = take_over_instance();
+    }
+    if lock.is_none() {
+        reply_instance_conflict(get_instance_conflict_description(&profile));
+    }
 
     prepare_log_file();
+    if let Some(profile) = get_profile() {
+        write_debug(format!("Instance profile: {}, Tor folder: {}", profile, get_tor_dir_path()));
+    }
+    if !custom_tor_dir && get_profile().is_none() {
+        paths::migrate_tor_dir(&paths::get_legacy_tor_dir(), Path::new(&get_tor_dir_path()));
+    }
     listen_for_sigterm();
+    if opts.daemon {
+        let code = match daemon::serve() {
+            Ok(_) => 0,
+            Err(err) => {
+                write_debug(format!("Can not run the daemon: {}", err));
+                1
+            }
+        };
+        shutdown::shutdown(code, &shutdown::ShutdownContext::current());
+    }
+    watchdog::watch_parent();
     write_debug("Waiting for messages");
     event_loop(messages::handler);
+    write_debug("Browser has closed the connection");
+    shutdown::shutdown(0, &shutdown::ShutdownContext::current());
+}
+
+/// Runs `f` with the log file of the profile and restores the log file afterwards,
+/// `lock_instance` adds the profile to the paths again.
+fn with_profile_log_file<T>(profile: Option<&str>, f: impl FnOnce() -> T) -> T {
+    let log_file = get_logfile_path();
+    if let Some(profile) = profile {
+        LOG_FILE.set(paths::get_profile_path(&log_file, profile));
+    }
+    let result = f();
+    LOG_FILE.set(log_file);
+    result
 }
 
+/// One request from the command line, through the same path as the messages of the browser.
+fn request(opts: cli::CliOptions, args: cli::RequestOptions) {
+    let msg = match args.get_message() {
+        Ok(msg) => msg,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let profile = get_cli_profile(&opts);
+    // the embedded Tor can not share the folder of a running instance
+    let _lock = match lock_instance(profile.clone(), MAX_INSTANCES) {
+        Some(lock) => lock,
+        None => {
+            eprintln!("{}", get_instance_conflict_description(&profile));
+            std::process::exit(1);
+        }
+    };
+    prepare_log_file();
+    listen_for_sigterm();
+    let id = msg.id.clone();
+    let res = match requests::get_response(msg) {
+        Ok(res) => res,
+        Err(err) => messages::get_internal_msg(id, 500, format!("Can not get response from resource: {:#?}", err)),
+    };
+    match serde_json::to_string_pretty(&res) {
+        Ok(json) => println!("{}", json),
+        Err(err) => eprintln!("Can not serialize response: {}", err),
+    }
+    // the response of the node is printed whatever its status
+    let code = if res.headers.contains_key("X-Alby-Internal") { 1 } else { 0 };
+    shutdown::shutdown(code, &shutdown::ShutdownContext::current());
+}
+
+fn get_instance_conflict_description(profile: &Option<String>) -> String {
+    match profile {
+        Some(profile) => format!("Companion for profile {} is already running", profile),
+        None => format!("All {} instances of the companion are already running", MAX_INSTANCES),
+    }
+}
 
 pub fn prepare_log_file() -> bool {
     let path = get_logfile_path();
@@ -120,11 +359,18 @@ fn write_debug_to<T: Display>(msg: T, log_file: &str, debug_mode: bool) -> bool
     true
 }
 
+#[cfg(feature = "embedded-tor")]
 fn get_random_port() -> u16 {
     let mut rng = thread_rng();
     rng.gen_range(19050..29051)
 }
 
+/// SOCKS port of the embedded Tor, 0 until it is selected.
+/// With `SocksPort auto` it is read back from the Tor control port by the bootstrap watcher.
+#[cfg(feature = "embedded-tor")]
+static TOR_PORT: AtomicU16 = AtomicU16::new(0);
+
+#[cfg(feature = "embedded-tor")]
 fn get_random_string() -> String {
     let mut rng = thread_rng();
     std::iter::repeat(())
@@ -134,34 +380,60 @@ fn get_random_string() -> String {
         .collect()
 }
 
+#[cfg(feature = "embedded-tor")]
 fn get_tor_port() -> u16 {
-    TOR_PORT.with(|tor_port| *tor_port.borrow())
+    TOR_PORT.load(Ordering::SeqCst)
+}
+
+#[cfg(feature = "embedded-tor")]
+fn set_tor_port(port: u16) {
+    TOR_PORT.store(port, Ordering::SeqCst)
+}
+
+/// Path of the unix socket the embedded Tor listens on for SOCKS connections, if used instead of a port.
+#[cfg(all(unix, feature = "embedded-tor"))]
+static TOR_SOCKS_SOCKET: Mutex<Option<String>> = Mutex::new(None);
+
+#[cfg(all(unix, feature = "embedded-tor"))]
+fn get_tor_socks_socket() -> Option<String> {
+    TOR_SOCKS_SOCKET.lock().ok().and_then(|s| s.clone())
 }
 
+#[cfg(all(unix, feature = "embedded-tor"))]
+fn set_tor_socks_socket(path: Option<String>) {
+    if let Ok(mut socket) = TOR_SOCKS_SOCKET.lock() {
+        *socket = path;
+    }
+}
+
+#[cfg(feature = "embedded-tor")]
 fn get_tor_username() -> String {
-    TOR_USERNAME.with(|v| {
-        let s: &str = v.borrow();
-        s.to_string()
-    })
+    TOR_USERNAME.get()
 }
 
+#[cfg(feature = "embedded-tor")]
 fn get_tor_password() -> String {
-    TOR_PASSWORD.with(|v| {
-        let s: &str = v.borrow();
-        s.to_string()
-    })
+    TOR_PASSWORD.get()
 }
 
 fn get_logfile_path() -> String {
-    LOG_FILE.with(|v| {
-        (*(v.borrow())).clone()
-    })
+    LOG_FILE.get()
 }
 
 fn get_tor_dir_path() -> String {
-    TOR_DIR.with(|v| {
-        (*(v.borrow())).clone()
-    })
+    TOR_DIR.get()
+}
+
+fn get_config_file_path() -> String {
+    CONFIG_FILE.get()
+}
+
+pub fn get_config() -> config::Config {
+    CONFIG.get()
+}
+
+pub fn set_config(val: config::Config) {
+    CONFIG.set(val)
 }
 
 fn get_lock_file_path() -> String {
@@ -170,15 +442,13 @@ fn get_lock_file_path() -> String {
 
 #[cfg(not(windows))]
 fn listen_for_sigterm() {
-    let lock_file = get_lock_file_path();
-    let log_file = get_logfile_path();
-    let debug_mode = is_debug_mode();
+    let ctx = shutdown::ShutdownContext::current();
     match Signals::new(TERM_SIGNALS) {
         Ok(mut signals) => {
             thread::spawn(move || {
-                for _ in signals.forever() {
-                    write_debug_to("SIGTERM received", &log_file, debug_mode);
-                    exit(0, lock_file.clone());
+                if signals.forever().next().is_some() {
+                    write_debug_to("SIGTERM received", &ctx.log_file, is_debug_mode());
+                    shutdown::shutdown(0, &ctx);
                 }
             });
         },
@@ -191,14 +461,9 @@ fn listen_for_sigterm() {
 #[cfg(windows)]
 fn listen_for_sigterm() {}
 
+/// Lock held for the process lifetime, the OS releases it when the process exits or crashes.
 struct LockFile {
-    path: String,
-}
-
-impl Drop for LockFile {
-    fn drop(&mut self) {
-        remove_lock_file(self.path.to_string());
-    }
+    _file: fs::File,
 }
 
 fn create_lock_file() -> Option<LockFile> {
@@ -207,54 +472,125 @@ fn create_lock_file() -> Option<LockFile> {
     // so all debug info should be printed to std_err.
     let debug_mode = is_debug_mode();
 
-    if Path::new(&path).exists() {
-        match fs::read_to_string(&path) {
-            Ok(pid_str) => match pid_str.trim().parse::<u32>() {
-                Err(_) => {
-                    if let Err(err) = fs::remove_file(&path) {
-                        if debug_mode {
-                            eprintln!("⚠️ Can't remove non-parseable lock file [{}]: {:#?}", &path, err);
-                        }
-                    }
-                },
-                Ok(pid) => match is_pid_exists(pid) {
-                    true => return None,
-                    false => {
-                        if let Err(err) = fs::remove_file(&path) {
-                            if debug_mode {
-                                eprintln!("⚠️ Can't remove lock file [{}] of non-existing process [{}]: {:#?}", &path, pid, err);
-                            }
-                        }
-                    }
-                }
-            },
-            Err(err) => {
-                if debug_mode {
-                    eprintln!("⚠️ Can't read PID from the lock file [{}]: {:#?}", &path, err);
+    // the file is never removed: another process may have it open and lock it after the removal
+    let file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path) {
+        Ok(f) => f,
+        Err(err) => {
+            eprintln!("Can not open the lock file [{}]: {:#?}", &path, err);
+            return None;
+        }
+    };
+    if let Err(err) = file.try_lock_exclusive() {
+        if debug_mode {
+            eprintln!("⚠️ Lock file [{}] is held by another process ({}): {}", &path, read_lock_owner(&path), err);
+        }
+        return None;
+    }
+    // PID and start time for diagnostics and the takeover, in another file as Windows doesn't let others read a locked file
+    let _ = fs::write(get_owner_file_path(&path), format!("{} {}", std::process::id(), chrono::Local::now().to_rfc3339()));
+    Some(LockFile { _file: file })
+}
+
+fn get_owner_file_path(lock_file: &str) -> String {
+    format!("{}.owner", lock_file)
+}
+
+fn read_lock_owner(path: &str) -> String {
+    match fs::read_to_string(get_owner_file_path(path)) {
+        Ok(owner) if !owner.trim().is_empty() => format!("PID and start: {}", owner.trim()),
+        _ => String::from("unknown"),
+    }
+}
+
+fn read_lock_owner_pid(path: &str) -> Option<u32> {
+    fs::read_to_string(get_owner_file_path(path)).ok()
+        .and_then(|owner| owner.split_whitespace().next().and_then(|pid| pid.parse::<u32>().ok()))
+}
+
+/// Locks the instance of the profile, or the first free slot without a profile.
+/// Every instance has its own log file, lock file and Tor folder, as Tor can not share its data directory.
+fn lock_instance(profile: Option<String>, max_slots: u8) -> Option<LockFile> {
+    let log_file = get_logfile_path();
+    let tor_dir = get_tor_dir_path();
+    if let Some(profile) = profile {
+        set_profile(&log_file, &tor_dir, profile);
+        return create_lock_file();
+    }
+    for slot in 1..=max_slots {
+        if slot > 1 {
+            set_profile(&log_file, &tor_dir, slot.to_string());
+        }
+        if let Some(lock) = create_lock_file() {
+            if slot > 1 {
+                let mut config = get_config();
+                // the fixed port is taken by the first instance
+                if config.tor.socks_port.unwrap_or(0) != 0 {
+                    config.tor.socks_port = None;
+                    set_config(config);
                 }
-                return None;
             }
+            return Some(lock);
         }
     }
+    // all slots are taken, the first one is the candidate for a takeover
+    LOG_FILE.set(log_file);
+    TOR_DIR.set(tor_dir);
+    PROFILE.set(None);
+    None
+}
 
-
-    match OpenOptions::new().write(true)
-        .create_new(true)
-        .open(&path) {
-        Ok(mut file) => {
-            let _ = write!(file, "{}", std::process::id());
-            Some(LockFile { path })
-        },
-        Err(err) => {
-            eprintln!("Can not create a lock file: {:#?}", err);
-            None
+/// Asks the instance holding the lock to shut down and waits until it releases the lock.
+fn take_over_instance() -> Option<LockFile> {
+    // the holder writes its PID right after locking
+    let pid = read_lock_owner_pid(&get_lock_file_path())?;
+    let mut sys = System::new();
+    let sys_pid = sysinfo::Pid::from_u32(pid);
+    sys.refresh_process_specifics(sys_pid, ProcessRefreshKind::new());
+    let process = sys.process(sys_pid)?;
+    // the PID may be reused by another program if the file was written by a crashed instance
+    let exe_name = std::env::current_exe().ok().and_then(|exe| exe.file_name().map(|n| n.to_os_string()));
+    if exe_name.is_none() || process.exe().file_name() != exe_name.as_deref() {
+        return None;
+    }
+    eprintln!("Asking the running instance {} to shut down", pid);
+    // SIGTERM runs its graceful shutdown, there is no such signal on Windows
+    if process.kill_with(Signal::Term).is_none() {
+        process.kill();
+    }
+    let deadline = std::time::Instant::now() + TAKEOVER_TIMEOUT;
+    while std::time::Instant::now() < deadline {
+        thread::sleep(std::time::Duration::from_millis(100));
+        if let Some(lock) = create_lock_file() {
+            return Some(lock);
         }
     }
+    None
+}
+
+/// Answers every message with the conflict, so the extension can explain it instead of seeing a closed port.
+fn reply_instance_conflict(description: String) -> ! {
+    eprintln!("{}", &description);
+    messages::send_stdout_msg(messages::get_instance_conflict_msg(String::from("status"), &description));
+    event_loop(|v: serde_json::Value| -> Result<messages::ResMessage, String> {
+        let id = v.get("id").and_then(|id| id.as_str()).unwrap_or("status").to_string();
+        Ok(messages::get_instance_conflict_msg(id, &description))
+    });
+    std::process::exit(1);
+}
+
+fn set_profile(log_file: &str, tor_dir: &str, profile: String) {
+    LOG_FILE.set(paths::get_profile_path(log_file, &profile));
+    TOR_DIR.set(paths::get_profile_path(tor_dir, &profile));
+    PROFILE.set(Some(profile));
+}
+
+pub fn get_profile() -> Option<String> {
+    PROFILE.get()
 }
 
 fn is_pid_exists(pid: u32) -> bool {
-    let sys = System::new_all();
-    sys.process(sysinfo::Pid::from_u32(pid)).is_some()
+    let mut sys = System::new();
+    sys.refresh_process_specifics(sysinfo::Pid::from_u32(pid), ProcessRefreshKind::new())
 }
 
 fn get_system_time() -> String {
@@ -263,16 +599,13 @@ fn get_system_time() -> String {
     dt.format("%d-%m-%Y %H:%M:%S").to_string()
 }
 
-fn remove_lock_file(path: String) {
-    let _ = fs::remove_file(path);
-}
-
-fn exit(code: i32, lock_file: String) {
-    remove_lock_file(lock_file);
+fn exit(code: i32) {
+    // the lock file is released by the OS
     std::process::exit(code);
 }
 
 
+#[cfg(test)]
 pub fn get_log(path: &str) -> String {
     match fs::read_to_string(path) {
         Ok(content) => content,
@@ -281,25 +614,37 @@ pub fn get_log(path: &str) -> String {
 }
 
 pub fn is_tor_started() -> bool {
-    TOR_STARTED.with(|v| *v.borrow())
+    TOR_STARTED.load(Ordering::SeqCst)
 }
 
+#[cfg(feature = "embedded-tor")]
 pub fn set_tor_is_started(val: bool) {
-    TOR_STARTED.with(|v| *v.borrow_mut() = val)
+    TOR_STARTED.store(val, Ordering::SeqCst)
+}
+
+/// Marks Tor as started, false if it already was: only one of the concurrent requests launches it
+#[cfg(feature = "embedded-tor")]
+pub fn try_set_tor_is_started() -> bool {
+    !TOR_STARTED.swap(true, Ordering::SeqCst)
 }
 
+#[cfg(feature = "embedded-tor")]
 pub fn is_tor_ready() -> bool {
-    TOR_READY.with(|v| *v.borrow())
+    TOR_READY.load(Ordering::SeqCst)
 }
 
+#[cfg(feature = "embedded-tor")]
 pub fn set_tor_is_ready(val: bool) {
-    TOR_READY.with(|v| *v.borrow_mut() = val)
+    TOR_READY.store(val, Ordering::SeqCst)
 }
 
+/// Shared by all threads, so `setDebugMode` reaches the Tor, signal and daemon threads too
+static DEBUG_MODE: AtomicBool = AtomicBool::new(false);
+
 pub fn is_debug_mode() -> bool {
-    DEBUG_MODE.with(|v| *v.borrow())
+    DEBUG_MODE.load(Ordering::SeqCst)
 }
 
 pub fn set_debug_mode(val: bool) {
-    DEBUG_MODE.with(|v| *v.borrow_mut() = val)
+    DEBUG_MODE.store(val, Ordering::SeqCst)
 }
@@ -5,7 +5,11 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value as SerdeValue;
 
 use crate::{is_debug_mode, write_debug};
+use crate::config::{load_config, LogLevel, merge_config, save_config, TorConfig};
+use crate::diagnose::DiagnoseRequest;
+use crate::routing::Route;
 use crate::requests::get_response;
+#[cfg(feature = "embedded-tor")]
 use crate::tor::wait_for_tor;
 
 #[derive(Deserialize, Debug)]
@@ -18,6 +22,8 @@ pub struct ReqMessage {
     pub headers: Option<HashMap<String, String>>,
     pub action: Option<String>,
     pub certificate: Option<String>,
+    /// `tor`, `direct` or `auto` (default)
+    pub route: Option<Route>,
 }
 
 #[derive(Serialize, Debug)]
@@ -39,6 +45,7 @@ impl Default for ReqMessage {
             headers: None,
             action: None,
             certificate: None,
+            route: None,
         }
     }
 }
@@ -54,6 +61,10 @@ pub fn handler(v: SerdeValue) -> Result<ResMessage, String> {
         Err(err) => return Err(format!("Can not parse message: \n{:#?}", err))
     };
     let id = msg.id.clone();
+    let _in_flight = match crate::shutdown::InFlight::start() {
+        Some(guard) => guard,
+        None => return Ok(get_internal_msg(id, 503, String::from("Companion is shutting down"))),
+    };
     match get_response_msg(msg) {
         Ok(response) => {
             if is_debug_mode() {
@@ -82,12 +93,41 @@ fn get_response_msg(msg: ReqMessage) -> Result<ResMessage, String>
             if crate::is_tor_started() {
                 return Ok(get_tor_started_msg());
             }
-            crate::tor::launch_tor();
-            return if wait_for_tor(30, &crate::get_logfile_path()) {
-                Ok(get_tor_started_msg())
-            } else {
-                Ok(get_tor_failed_start_msg())
+            if let Some(proxy) = crate::proxy::get_external_proxy() {
+                write_debug(format!("Using running Tor on {}:{} instead of launching it", &proxy.host, proxy.port));
+                return Ok(get_tor_started_msg());
             }
+            return Ok(start_embedded_tor());
+        }
+        if action == "configureTor" {
+            return configure_tor(msg);
+        }
+        if action == "getConfig" {
+            return get_config(msg);
+        }
+        if action == "setConfig" {
+            return set_config(msg);
+        }
+        if action == "setDebugMode" {
+            return set_debug_mode(msg);
+        }
+        if action == "setLogLevel" {
+            return set_log_level(msg);
+        }
+        if action == "getSettings" {
+            return get_settings(msg);
+        }
+        if action == "addOnionAuth" {
+            return add_onion_auth(msg);
+        }
+        if action == "diagnose" {
+            return diagnose(msg);
+        }
+        if action == "addOnionService" {
+            return add_onion_service(msg);
+        }
+        if action == "removeOnionService" {
+            return remove_onion_service(msg);
         }
     }
     match get_response(msg) {
@@ -96,6 +136,271 @@ fn get_response_msg(msg: ReqMessage) -> Result<ResMessage, String>
     }
 }
 
+#[cfg(feature = "embedded-tor")]
+fn start_embedded_tor() -> ResMessage {
+    crate::tor::launch_tor();
+    match wait_for_tor(crate::get_config().timeouts.get_tor_bootstrap()) {
+        Ok(_) => get_tor_started_msg(),
+        Err(err) => {
+            write_debug(format!("Tor is not ready: {}", err));
+            get_tor_failed_start_msg()
+        }
+    }
+}
+
+#[cfg(not(feature = "embedded-tor"))]
+fn start_embedded_tor() -> ResMessage {
+    write_debug("Tor is not available in this build and no Tor proxy is configured");
+    get_tor_unavailable_msg()
+}
+
+fn configure_tor(msg: ReqMessage) -> Result<ResMessage, String> {
+    let tor_config: TorConfig = match &msg.body {
+        Some(body) => serde_json::from_str(body).map_err(|e| format!("Can not parse Tor configuration: {}", e))?,
+        None => return Err(String::from("Tor configuration is missing in the message body")),
+    };
+    // the file keeps its other settings, the options of the command line and the environment are not saved
+    let path = crate::get_config_file_path();
+    let mut file_config = load_config(&path)?;
+    file_config.tor = tor_config.clone();
+    save_config(&path, &file_config)?;
+    let mut config = crate::get_config();
+    config.tor = tor_config;
+    let body = serde_json::to_string(&config.tor).map_err(|e| format!("Can not serialize Tor configuration: {}", e))?;
+    crate::set_config(config);
+    let mut res = get_internal_msg(msg.id, 200, body);
+    if crate::is_tor_started() {
+        res.headers.insert("X-Alby-description".to_string(), "Tor is already running, restart the companion app to apply the configuration".to_string());
+    }
+    Ok(res)
+}
+
+fn get_config(msg: ReqMessage) -> Result<ResMessage, String> {
+    Ok(get_internal_msg(msg.id, 200, get_config_body()?))
+}
+
+/// Merges the fields of the body into the config file and the config in effect.
+/// Routing and timeouts apply to the next request, the log level right away.
+fn set_config(msg: ReqMessage) -> Result<ResMessage, String> {
+    let patch: SerdeValue = match &msg.body {
+        Some(body) => serde_json::from_str(body).map_err(|e| format!("Can not parse config: {}", e))?,
+        None => return Err(String::from("Config is missing in the message body")),
+    };
+    let path = crate::get_config_file_path();
+    let file_config = merge_config(&load_config(&path)?, patch.clone())?;
+    let config = merge_config(&crate::get_config(), patch.clone())?;
+    save_config(&path, &file_config)?;
+    if !patch["log"]["level"].is_null() {
+        crate::set_debug_mode(config.log.level == LogLevel::Debug);
+    }
+    crate::set_config(config);
+    write_debug(format!("Config file {} updated", &path));
+    let mut res = get_internal_msg(msg.id, 200, get_config_body()?);
+    let needs_restart = !patch["log"]["file"].is_null() || !patch["torDir"].is_null()
+        || (!patch["tor"].is_null() && crate::is_tor_started());
+    if needs_restart {
+        res.headers.insert("X-Alby-description".to_string(), "Restart the companion app to apply the configuration".to_string());
+    }
+    Ok(res)
+}
+
+/// The config file and the config in effect, with the options of the command line and the environment
+fn get_config_body() -> Result<String, String> {
+    let path = crate::get_config_file_path();
+    let body = serde_json::json!({
+        "file": &path,
+        "config": load_config(&path)?,
+        "effective": crate::get_config(),
+    });
+    Ok(body.to_string())
+}
+
+#[derive(Deserialize, Debug)]
+struct DebugModeRequest {
+    enabled: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct LogLevelRequest {
+    level: LogLevel,
+}
+
+/// The change lasts only until the companion exits, `setConfig` is the way to persist it.
+fn set_debug_mode(msg: ReqMessage) -> Result<ResMessage, String> {
+    let req: DebugModeRequest = match &msg.body {
+        Some(body) => serde_json::from_str(body).map_err(|e| format!("Can not parse debug mode: {}", e))?,
+        None => return Err(String::from("Debug mode is missing in the message body")),
+    };
+    apply_log_level(if req.enabled { LogLevel::Debug } else { LogLevel::Info });
+    Ok(get_internal_msg(msg.id, 200, get_settings_body()))
+}
+
+fn set_log_level(msg: ReqMessage) -> Result<ResMessage, String> {
+    let req: LogLevelRequest = match &msg.body {
+        Some(body) => serde_json::from_str(body).map_err(|e| format!("Can not parse log level: {}", e))?,
+        None => return Err(String::from("Log level is missing in the message body")),
+    };
+    apply_log_level(req.level);
+    Ok(get_internal_msg(msg.id, 200, get_settings_body()))
+}
+
+fn apply_log_level(level: LogLevel) {
+    let mut config = crate::get_config();
+    config.log.level = level;
+    crate::set_config(config);
+    crate::set_debug_mode(level == LogLevel::Debug);
+    write_debug(format!("Log level: {:?}", level));
+}
+
+fn get_settings(msg: ReqMessage) -> Result<ResMessage, String> {
+    Ok(get_internal_msg(msg.id, 200, get_settings_body()))
+}
+
+/// Settings of the running companion for the troubleshooting page
+fn get_settings_body() -> String {
+    let level = match is_debug_mode() {
+        true => LogLevel::Debug,
+        false => LogLevel::Info,
+    };
+    let body = serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "logLevel": level,
+        "debugMode": is_debug_mode(),
+        "logFile": crate::get_logfile_path(),
+        "torDir": crate::get_tor_dir_path(),
+        "configFile": crate::get_config_file_path(),
+        "profile": crate::get_profile(),
+        "torStarted": crate::is_tor_started(),
+        "embeddedTor": cfg!(feature = "embedded-tor"),
+    });
+    body.to_string()
+}
+
+#[cfg(feature = "embedded-tor")]
+fn add_onion_auth(msg: ReqMessage) -> Result<ResMessage, String> {
+    use crate::onion_auth::{apply_onion_auth, OnionAuthRequest, parse_onion_address, parse_private_key, save_onion_auth};
+
+    let req: OnionAuthRequest = match &msg.body {
+        Some(body) => serde_json::from_str(body).map_err(|e| format!("Can not parse onion authorization: {}", e))?,
+        None => return Err(String::from("Onion authorization is missing in the message body")),
+    };
+    let address = parse_onion_address(&req.onion)?;
+    let key = parse_private_key(&req.private_key)?;
+    let tor_dir = crate::get_tor_dir_path();
+    save_onion_auth(&tor_dir, &address, &key)?;
+    write_debug(format!("Client authorization saved for {}.onion", &address));
+    // otherwise Tor reads it from ClientOnionAuthDir on start
+    let mut applied = false;
+    if crate::is_tor_ready() {
+        match apply_onion_auth(&tor_dir, &address, &key) {
+            Ok(_) => applied = true,
+            Err(err) => {
+                write_debug(format!("Can not add client authorization to the running Tor: {}", err));
+            }
+        }
+    }
+    let body = serde_json::json!({ "onion": format!("{}.onion", &address), "applied": applied }).to_string();
+    Ok(get_internal_msg(msg.id, 200, body))
+}
+
+#[cfg(not(feature = "embedded-tor"))]
+fn add_onion_auth(msg: ReqMessage) -> Result<ResMessage, String> {
+    write_debug(format!("[{}]\t Onion client authorization requires the embedded Tor", &msg.id));
+    Ok(get_tor_unavailable_msg())
+}
+
+fn diagnose(msg: ReqMessage) -> Result<ResMessage, String> {
+    let req: DiagnoseRequest = match &msg.body {
+        Some(body) if !body.trim().is_empty() => serde_json::from_str(body).map_err(|e| format!("Can not parse diagnose request: {}", e))?,
+        _ => Default::default(),
+    };
+    let report = crate::diagnose::diagnose(&req);
+    let body = serde_json::to_string(&report).map_err(|e| format!("Can not serialize diagnose report: {}", e))?;
+    if is_debug_mode() {
+        write_debug(format!("Diagnose report: {}", &body));
+    }
+    Ok(get_internal_msg(msg.id, 200, body))
+}
+
+#[cfg(feature = "embedded-tor")]
+fn add_onion_service(msg: ReqMessage) -> Result<ResMessage, String> {
+    use crate::onion_service::AddOnionServiceRequest;
+
+    let req: AddOnionServiceRequest = match &msg.body {
+        Some(body) => serde_json::from_str(body).map_err(|e| format!("Can not parse onion service: {}", e))?,
+        None => return Err(String::from("Onion service is missing in the message body")),
+    };
+    ensure_embedded_tor()?;
+    let service = crate::onion_service::add_onion_service(&crate::get_tor_dir_path(), &req)?;
+    write_debug(format!("Onion service {} forwards to {}", service.get_onion_address(), &service.target));
+    let body = serde_json::json!({
+        "onion": service.get_onion_address(),
+        "serviceId": &service.service_id,
+        "virtualPort": service.virtual_port,
+        "target": &service.target,
+        "persistent": req.persistent,
+    }).to_string();
+    Ok(get_internal_msg(msg.id, 200, body))
+}
+
+#[cfg(feature = "embedded-tor")]
+fn remove_onion_service(msg: ReqMessage) -> Result<ResMessage, String> {
+    use crate::onion_service::RemoveOnionServiceRequest;
+
+    let req: RemoveOnionServiceRequest = match &msg.body {
+        Some(body) => serde_json::from_str(body).map_err(|e| format!("Can not parse onion service: {}", e))?,
+        None => return Err(String::from("Onion service is missing in the message body")),
+    };
+    let removed = crate::onion_service::remove_onion_service(&crate::get_tor_dir_path(), &req.service_id)?;
+    write_debug(format!("Onion service {} removed: {}", &req.service_id, removed));
+    let status = if removed { 200 } else { 404 };
+    let body = serde_json::json!({ "serviceId": &req.service_id, "removed": removed }).to_string();
+    Ok(get_internal_msg(msg.id, status, body))
+}
+
+#[cfg(not(feature = "embedded-tor"))]
+fn add_onion_service(msg: ReqMessage) -> Result<ResMessage, String> {
+    write_debug(format!("[{}]\t Onion services require the embedded Tor", &msg.id));
+    Ok(get_tor_unavailable_msg())
+}
+
+#[cfg(not(feature = "embedded-tor"))]
+fn remove_onion_service(msg: ReqMessage) -> Result<ResMessage, String> {
+    add_onion_service(msg)
+}
+
+/// Launches the embedded Tor if needed and waits until it is bootstrapped.
+#[cfg(feature = "embedded-tor")]
+fn ensure_embedded_tor() -> Result<(), String> {
+    if !crate::is_tor_started() {
+        crate::tor::launch_tor();
+    }
+    if !crate::is_tor_ready() {
+        wait_for_tor(crate::get_config().timeouts.get_tor_bootstrap())?;
+    }
+    Ok(())
+}
+
+pub fn get_internal_msg(id: String, status: u16, body: String) -> ResMessage {
+    ResMessage {
+        id,
+        status,
+        body,
+        headers: HashMap::from([("X-Alby-Internal".to_string(), "true".to_string())]),
+    }
+}
+
+#[cfg(not(feature = "embedded-tor"))]
+pub fn get_tor_unavailable_msg() -> ResMessage {
+    ResMessage {
+        id: "status".to_string(),
+        status: 501,
+        body: String::from("Tor not available in this build"),
+        headers: HashMap::from([("X-Alby-Internal".to_string(), "true".to_string())]),
+    }
+}
+
+#[cfg(feature = "embedded-tor")]
 pub fn get_tor_failed_start_msg() -> ResMessage {
     ResMessage {
         id: "status".to_string(),
@@ -106,6 +411,19 @@ pub fn get_tor_failed_start_msg() -> ResMessage {
 }
 
 
+/// Another instance holds the lock of this profile
+pub fn get_instance_conflict_msg(id: String, description: &str) -> ResMessage {
+    ResMessage {
+        id,
+        status: 409,
+        body: String::from("instance_conflict"),
+        headers: HashMap::from([
+            ("X-Alby-Internal".to_string(), "true".to_string()),
+            ("X-Alby-description".to_string(), description.to_string()),
+        ]),
+    }
+}
+
 pub fn get_tor_started_msg() -> ResMessage {
     ResMessage {
         id: "status".to_string(),
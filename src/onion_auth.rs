@@ -0,0 +1,121 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::control::TorControl;
+
+/// Folder inside the Tor data directory used as `ClientOnionAuthDir`
+pub const ONION_AUTH_DIR: &str = "onion-auth";
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OnionAuthRequest {
+    pub onion: String,
+    pub private_key: String,
+}
+
+pub fn get_onion_auth_dir(tor_dir: &str) -> PathBuf {
+    Path::new(tor_dir).join(ONION_AUTH_DIR)
+}
+
+/// `http://abc...xyz.onion:8080/` => `abc...xyz` (v3 addresses only)
+pub fn parse_onion_address(onion: &str) -> Result<String, String> {
+    let host = match reqwest::Url::parse(onion) {
+        Ok(url) if url.host_str().is_some() => url.host_str().unwrap_or_default().to_string(),
+        _ => onion.trim().to_string(),
+    };
+    let address = host.to_lowercase().trim_end_matches(".onion").to_string();
+    let is_base32 = address.bytes().all(|c| c.is_ascii_lowercase() || (b'2'..=b'7').contains(&c));
+    if address.len() != 56 || !is_base32 {
+        return Err(format!("{} is not a v3 onion address", onion));
+    }
+    Ok(address)
+}
+
+/// Accepts `descriptor:x25519:KEY`, `x25519:KEY` or `KEY`, with the key in base32 (`.auth_private` files) or base64.
+pub fn parse_private_key(key: &str) -> Result<Vec<u8>, String> {
+    let key = key.trim();
+    let key = key.strip_prefix("descriptor:").unwrap_or(key);
+    let key = key.strip_prefix("x25519:").unwrap_or(key);
+    let bytes = match base32_decode(key) {
+        Some(bytes) if bytes.len() == 32 => bytes,
+        _ => base64::decode(key).map_err(|_| String::from("Private key is neither base32 nor base64"))?,
+    };
+    if bytes.len() != 32 {
+        return Err(format!("x25519 private key must be 32 bytes long, got {}", bytes.len()));
+    }
+    Ok(bytes)
+}
+
+/// Stores the key as `<address>.auth_private`, Tor reads these files at startup.
+pub fn save_onion_auth(tor_dir: &str, address: &str, key: &[u8]) -> Result<(), String> {
+    let dir = get_onion_auth_dir(tor_dir);
+    create_private_dir(&dir)?;
+    let path = dir.join(format!("{}.auth_private", address));
+    let content = format!("{}:descriptor:x25519:{}\n", address, base32_encode(key));
+    fs::write(&path, content).map_err(|e| format!("Can not write {}: {}", path.to_string_lossy(), e))?;
+    set_private_permissions(&path, 0o600)
+}
+
+/// Adds the key to the running Tor, so it can be used without restart.
+pub fn apply_onion_auth(tor_dir: &str, address: &str, key: &[u8]) -> Result<(), String> {
+    let mut control = TorControl::connect(tor_dir)?;
+    control.command(&format!("ONION_CLIENT_AUTH_ADD {} x25519:{}", address, base64::encode(key)))?;
+    Ok(())
+}
+
+pub fn create_private_dir(dir: &Path) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| format!("Can not create folder {}: {}", dir.to_string_lossy(), e))?;
+    set_private_permissions(dir, 0o700)
+}
+
+#[cfg(unix)]
+pub fn set_private_permissions(path: &Path, mode: u32) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .map_err(|e| format!("Can not set permissions of {}: {}", path.to_string_lossy(), e))
+}
+
+#[cfg(not(unix))]
+pub fn set_private_permissions(_path: &Path, _mode: u32) -> Result<(), String> {
+    Ok(())
+}
+
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in s.trim_end_matches('=').bytes() {
+        let val = BASE32_ALPHABET.iter().position(|&a| a == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | val;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut s = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &b in bytes {
+        buffer = (buffer << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            s.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        s.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    s
+}
@@ -0,0 +1,176 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::control::TorControl;
+use crate::onion_auth::{create_private_dir, parse_onion_address};
+
+/// Folder inside the Tor data directory with the keys of the persistent onion services
+pub const ONION_SERVICES_DIR: &str = "onion-services";
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AddOnionServiceRequest {
+    /// Local port to forward to, e.g. 8080 for LND REST
+    pub port: u16,
+    /// Host to forward to, `127.0.0.1` by default
+    pub host: Option<String>,
+    /// Port of the onion address, the same as `port` by default
+    pub virtual_port: Option<u16>,
+    /// Keep the service (and its address) across restarts
+    #[serde(default)]
+    pub persistent: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoveOnionServiceRequest {
+    pub service_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OnionService {
+    pub service_id: String,
+    pub virtual_port: u16,
+    pub target: String,
+    /// `ED25519-V3:<key>`, only for persistent services
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<String>,
+}
+
+impl OnionService {
+    pub fn get_onion_address(&self) -> String {
+        format!("{}.onion", self.service_id)
+    }
+}
+
+pub fn get_onion_services_dir(tor_dir: &str) -> PathBuf {
+    Path::new(tor_dir).join(ONION_SERVICES_DIR)
+}
+
+/// Publishes a new v3 onion service on the running Tor.
+pub fn add_onion_service(tor_dir: &str, req: &AddOnionServiceRequest) -> Result<OnionService, String> {
+    if req.port == 0 || req.virtual_port == Some(0) {
+        return Err(String::from("Port of the onion service is missing"));
+    }
+    let target = get_target(req.host.as_deref().unwrap_or("127.0.0.1"), req.port)?;
+    let virtual_port = req.virtual_port.unwrap_or(req.port);
+    let mut control = TorControl::connect(tor_dir)?;
+    // Detach keeps the service after this control connection is closed
+    let flags = match req.persistent {
+        true => "Detach",
+        false => "Detach,DiscardPK",
+    };
+    let reply = control.command(&format!("ADD_ONION NEW:ED25519-V3 Flags={} Port={},{}", flags, virtual_port, &target))?;
+    let service_id = reply.lines.iter()
+        .find_map(|l| l.strip_prefix("ServiceID="))
+        .ok_or_else(|| String::from("Tor did not return the onion service ID"))?
+        .to_string();
+    let private_key = reply.lines.iter()
+        .find_map(|l| l.strip_prefix("PrivateKey="))
+        .map(|k| k.to_string());
+    let service = OnionService { service_id, virtual_port, target, private_key };
+    if req.persistent {
+        if let Err(err) = save_onion_service(tor_dir, &service) {
+            let _ = control.command(&format!("DEL_ONION {}", &service.service_id));
+            return Err(err);
+        }
+    }
+    Ok(service)
+}
+
+/// `host:port` for the `Port` argument of `ADD_ONION`. The host goes into a control port command,
+/// so only IP addresses and plain host names are accepted.
+pub fn get_target(host: &str, port: u16) -> Result<String, String> {
+    let host = host.trim();
+    if let Ok(ip) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host).parse::<IpAddr>() {
+        return Ok(match ip {
+            IpAddr::V4(ip) => format!("{}:{}", ip, port),
+            IpAddr::V6(ip) => format!("[{}]:{}", ip, port),
+        });
+    }
+    let is_hostname = !host.is_empty() && host.len() <= 253
+        && host.split('.').all(|label| !label.is_empty() && !label.starts_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+    match is_hostname {
+        true => Ok(format!("{}:{}", host, port)),
+        false => Err(format!("Invalid host of the onion service: {}", host)),
+    }
+}
+
+/// Removes the service from the running Tor (if it runs) and deletes its key.
+pub fn remove_onion_service(tor_dir: &str, service_id: &str) -> Result<bool, String> {
+    let service_id = parse_onion_address(service_id).map_err(|_| format!("Invalid onion service ID: {}", service_id))?;
+    let mut removed = false;
+    let path = get_onion_services_dir(tor_dir).join(format!("{}.json", service_id));
+    if path.exists() {
+        fs::remove_file(&path).map_err(|e| format!("Can not remove {}: {}", path.to_string_lossy(), e))?;
+        removed = true;
+    }
+    if let Ok(mut control) = TorControl::connect(tor_dir) {
+        if control.command(&format!("DEL_ONION {}", service_id)).is_ok() {
+            removed = true;
+        }
+    }
+    Ok(removed)
+}
+
+/// Publishes the persistent services again after Tor start, returns the result for each of them.
+pub fn restore_onion_services(control: &mut TorControl, tor_dir: &str) -> Vec<Result<String, String>> {
+    load_onion_services(tor_dir).into_iter().map(|service| {
+        let service = service?;
+        let key = service.private_key.as_deref().unwrap_or_default();
+        let command = format!("ADD_ONION {} Flags=Detach Port={},{}", key, service.virtual_port, &service.target);
+        match control.command(&command) {
+            Ok(_) => Ok(service.get_onion_address()),
+            Err(err) => Err(format!("Can not publish {}: {}", service.get_onion_address(), err)),
+        }
+    }).collect()
+}
+
+/// Persistent services saved in the Tor data directory. They are checked like new ones,
+/// the files are replayed to the control port.
+pub fn load_onion_services(tor_dir: &str) -> Vec<Result<OnionService, String>> {
+    let entries = match fs::read_dir(get_onion_services_dir(tor_dir)) {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+    let mut services = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let service = fs::read_to_string(&path).map_err(|e| e.to_string())
+            .and_then(|content| serde_json::from_str::<OnionService>(&content).map_err(|e| e.to_string()))
+            .and_then(|service| check_saved_service(&service).map(|_| service));
+        services.push(service.map_err(|err| format!("Can not read onion service {}: {}", path.to_string_lossy(), err)));
+    }
+    services
+}
+
+fn check_saved_service(service: &OnionService) -> Result<(), String> {
+    parse_onion_address(&service.service_id)?;
+    let (host, port) = service.target.rsplit_once(':').ok_or_else(|| format!("Invalid target {}", &service.target))?;
+    let port = port.parse::<u16>().map_err(|_| format!("Invalid target {}", &service.target))?;
+    if service.virtual_port == 0 || port == 0 || get_target(host, port)? != service.target {
+        return Err(format!("Invalid target {}", &service.target));
+    }
+    let key = service.private_key.as_deref().and_then(|k| k.strip_prefix("ED25519-V3:")).unwrap_or_default();
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/' || c == '=') {
+        return Err(String::from("Private key is missing or invalid"));
+    }
+    Ok(())
+}
+
+pub fn save_onion_service(tor_dir: &str, service: &OnionService) -> Result<(), String> {
+    let dir = get_onion_services_dir(tor_dir);
+    create_private_dir(&dir)?;
+    let path = dir.join(format!("{}.json", &service.service_id));
+    let content = serde_json::to_string_pretty(service).map_err(|e| format!("Can not serialize onion service: {}", e))?;
+    fs::write(&path, content).map_err(|e| format!("Can not write {}: {}", path.to_string_lossy(), e))?;
+    crate::onion_auth::set_private_permissions(&path, 0o600)
+}
@@ -0,0 +1,146 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::write_debug;
+
+/// Per-user folder for persistent data:
+/// `$XDG_DATA_HOME/alby` (`~/.local/share/alby`) on Linux,
+/// `~/Library/Application Support/alby` on macOS and `%LOCALAPPDATA%\alby` on Windows.
+pub fn get_data_dir() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        get_env_path("LOCALAPPDATA").or_else(|| get_env_path("APPDATA"))
+    } else if cfg!(target_os = "macos") {
+        get_env_path("HOME").map(|home| home.join("Library").join("Application Support"))
+    } else {
+        get_env_path("XDG_DATA_HOME").or_else(|| get_env_path("HOME").map(|home| home.join(".local").join("share")))
+    };
+    base.map(|dir| dir.join("alby"))
+}
+
+/// Per-user folder for settings:
+/// `$XDG_CONFIG_HOME/alby` (`~/.config/alby`) on Linux,
+/// `~/Library/Application Support/alby` on macOS and `%APPDATA%\alby` on Windows.
+pub fn get_config_dir() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        get_env_path("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        get_env_path("HOME").map(|home| home.join("Library").join("Application Support"))
+    } else {
+        get_env_path("XDG_CONFIG_HOME").or_else(|| get_env_path("HOME").map(|home| home.join(".config")))
+    };
+    base.map(|dir| dir.join("alby"))
+}
+
+/// Config file in the temp folder, where it was kept before the per-user config folder
+pub fn get_legacy_config_file() -> PathBuf {
+    std::env::temp_dir().join("alby-config.json")
+}
+
+pub fn get_default_config_file() -> String {
+    match get_config_dir() {
+        Some(dir) => dir.join("config.json").to_string_lossy().to_string(),
+        None => get_legacy_config_file().to_string_lossy().to_string(),
+    }
+}
+
+/// Copies the config file from the old temporary folder, it is kept for the older versions.
+pub fn migrate_config_file(from: &Path, to: &Path) -> bool {
+    if from == to || !from.is_file() || to.exists() {
+        return false;
+    }
+    if let Some(parent) = to.parent() {
+        if fs::create_dir_all(parent).is_err() {
+            return false;
+        }
+    }
+    fs::copy(from, to).is_ok()
+}
+
+/// Tor folder in the temp folder, where it was kept before the per-user data folder. Many OSes purge it.
+pub fn get_legacy_tor_dir() -> PathBuf {
+    std::env::temp_dir().join("alby-tor")
+}
+
+pub fn get_default_tor_dir() -> String {
+    match get_data_dir() {
+        Some(dir) => dir.join("tor").to_string_lossy().to_string(),
+        None => get_legacy_tor_dir().to_string_lossy().to_string(),
+    }
+}
+
+/// File with the port and the access token of the running daemon of the profile
+pub fn get_daemon_file(profile: Option<&str>) -> String {
+    let path = get_data_dir().unwrap_or_else(std::env::temp_dir).join("daemon.json").to_string_lossy().to_string();
+    match profile {
+        Some(profile) => get_profile_path(&path, profile),
+        None => path,
+    }
+}
+
+/// Path of a file or folder of the given instance profile: `alby.log` => `alby-work.log`, `tor` => `tor-work`
+pub fn get_profile_path(path: &str, profile: &str) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, profile, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, profile),
+    };
+    path.with_file_name(name).to_string_lossy().to_string()
+}
+
+/// Profile names become parts of file names
+pub fn is_valid_profile(profile: &str) -> bool {
+    !profile.is_empty() && profile.len() <= 64
+        && profile.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn get_env_path(name: &str) -> Option<PathBuf> {
+    match std::env::var_os(name) {
+        Some(val) if !val.is_empty() => Some(PathBuf::from(val)),
+        _ => None,
+    }
+}
+
+/// Moves cached Tor state (consensus, descriptors, guards) from the old temporary folder,
+/// so the first launch after the update doesn't bootstrap from scratch.
+pub fn migrate_tor_dir(from: &Path, to: &Path) -> bool {
+    if from == to || !from.is_dir() || to.exists() {
+        return false;
+    }
+    if let Some(parent) = to.parent() {
+        if let Err(err) = fs::create_dir_all(parent) {
+            write_debug(format!("Can not create folder {}: {}", parent.to_string_lossy(), err));
+            return false;
+        }
+    }
+    // rename doesn't work across file systems (tmpfs => home), copy in this case
+    if fs::rename(from, to).is_err() {
+        if let Err(err) = copy_dir(from, to) {
+            write_debug(format!("Can not copy Tor folder {} to {}: {}", from.to_string_lossy(), to.to_string_lossy(), err));
+            let _ = fs::remove_dir_all(to);
+            return false;
+        }
+        let _ = fs::remove_dir_all(from);
+    }
+    write_debug(format!("Tor folder migrated from {} to {}", from.to_string_lossy(), to.to_string_lossy()));
+    true
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        // `lock` belongs to the Tor process which used the folder
+        if entry.file_name() == "lock" {
+            continue;
+        }
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::ProxyConfig;
+use crate::write_debug;
+
+/// Ports of the system Tor daemon and of Tor Browser
+const SYSTEM_TOR_PORTS: [u16; 2] = [9050, 9150];
+
+/// Result of `detect_system_tor`, the ports are probed again after `forget_system_tor`
+static SYSTEM_TOR: Mutex<Option<Option<ProxyConfig>>> = Mutex::new(None);
+
+/// External SOCKS proxy to use for onion requests instead of the embedded Tor, if any.
+pub fn get_external_proxy() -> Option<ProxyConfig> {
+    let tor_config = crate::get_config().tor;
+    if let Some(proxy) = tor_config.proxy {
+        return Some(proxy);
+    }
+    if tor_config.use_system_tor {
+        return get_system_tor();
+    }
+    None
+}
+
+/// Detected once, a probe takes up to a second when no Tor is running.
+fn get_system_tor() -> Option<ProxyConfig> {
+    let mut system_tor = SYSTEM_TOR.lock().unwrap_or_else(|e| e.into_inner());
+    system_tor.get_or_insert_with(detect_system_tor).clone()
+}
+
+/// After a failed connection through the detected Tor, it may have been stopped or started elsewhere.
+pub fn forget_system_tor() {
+    *SYSTEM_TOR.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+pub fn detect_system_tor() -> Option<ProxyConfig> {
+    for port in SYSTEM_TOR_PORTS {
+        let proxy = ProxyConfig {
+            host: String::from("127.0.0.1"),
+            port,
+            ..Default::default()
+        };
+        if is_socks5_proxy(&proxy) {
+            write_debug(format!("Running Tor detected on port {}", port));
+            return Some(proxy);
+        }
+    }
+    write_debug("Running Tor was not detected");
+    None
+}
+
+/// Checks that a SOCKS5 server answers the greeting (RFC 1928) on the given address.
+pub fn is_socks5_proxy(proxy: &ProxyConfig) -> bool {
+    let addr: SocketAddr = match (proxy.host.as_str(), proxy.port).to_socket_addrs().ok().and_then(|mut a| a.next()) {
+        Some(a) => a,
+        None => return false,
+    };
+    let timeout = Duration::from_millis(500);
+    let mut stream = match TcpStream::connect_timeout(&addr, timeout) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+    // version 5, two methods: "no authentication" and "username/password"
+    if stream.write_all(&[0x05, 0x02, 0x00, 0x02]).is_err() {
+        return false;
+    }
+    let mut reply = [0u8; 2];
+    match stream.read_exact(&mut reply) {
+        Ok(_) => reply[0] == 0x05 && reply[1] != 0xFF,
+        Err(_) => false,
+    }
+}
+
+impl ProxyConfig {
+    pub fn get_url(&self) -> String {
+        format!("socks5h://{}:{}", self.host, self.port)
+    }
+
+    pub fn get_reqwest_proxy(&self) -> reqwest::Result<reqwest::Proxy> {
+        let proxy = reqwest::Proxy::all(self.get_url())?;
+        Ok(match &self.username {
+            Some(username) => proxy.basic_auth(username, self.password.as_deref().unwrap_or_default()),
+            None => proxy,
+        })
+    }
+}
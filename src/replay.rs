@@ -0,0 +1,39 @@
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+
+use crate::cli::ReplayOptions;
+use crate::framing::{Host, parse_session, write_frame};
+
+/// Pipes the messages of a captured session into the host and prints its responses as JSON lines.
+pub fn replay(opts: &ReplayOptions) -> Result<(), String> {
+    let bytes = fs::read(&opts.file).map_err(|e| format!("Can not read {}: {}", &opts.file, e))?;
+    let messages = parse_session(&bytes)?;
+    let program = match &opts.host {
+        Some(path) => PathBuf::from(path),
+        None => std::env::current_exe().map_err(|e| format!("Can not get the path of the executable: {}", e))?,
+    };
+    let mut host = Host::spawn(&program, &opts.host_args)?;
+    let mut input = host.input.take().ok_or_else(|| String::from("Input of the host is not piped"))?;
+    let sent = messages.len();
+    // written by another thread, the host blocks when its output is not read
+    let writer = thread::spawn(move || -> Result<(), String> {
+        for message in &messages {
+            write_frame(&mut input, message)?;
+        }
+        // the input is closed with the end of the session, the host exits then
+        Ok(())
+    });
+    let mut received = 0;
+    while let Some(response) = host.receive()? {
+        println!("{}", response);
+        received += 1;
+    }
+    writer.join().map_err(|_| String::from("Can not write the session"))??;
+    let status = host.close()?;
+    eprintln!("{} message(s) sent, {} response(s) received, host {}", sent, received, status);
+    match status.success() {
+        true => Ok(()),
+        false => Err(format!("Host has exited with {}", status)),
+    }
+}
@@ -1,11 +1,16 @@
 use std::collections::HashMap;
 use std::fmt::{Debug, Display};
+use std::sync::Mutex;
 use std::time::Duration;
 
 use reqwest::header::HeaderMap;
 
-use crate::{get_tor_password, get_tor_port, get_tor_username, is_debug_mode, write_debug};
+use crate::{is_debug_mode, write_debug};
+#[cfg(feature = "embedded-tor")]
+use crate::{get_tor_password, get_tor_port, get_tor_username};
+use crate::config::ProxyConfig;
 use crate::messages::{ReqMessage, ResMessage};
+use crate::routing::{accepts_invalid_certs, get_route, Route};
 
 #[derive(Debug)]
 pub enum ReqError {
@@ -30,53 +35,41 @@ pub fn get_response(message: ReqMessage) -> Result<ResMessage, ReqError> {
         Ok(u) => u,
         Err(err) => return Err(ReqError::Message(format!("[{}]\t Can not parse URL: {}", &id, err)))
     };
-    let is_clearnet = match url.domain() {
-        Some(host) => !host.contains(".onion"),
-        None => false,
+    let route = match get_route(&url, message.route, &crate::get_config().routing) {
+        Ok(r) => r,
+        Err(err) => return Err(ReqError::Message(format!("[{}]\t {}", &id, err)))
     };
+    let is_clearnet = route == Route::Direct;
+    // onion services and LAN nodes commonly use self-signed certificates
+    let is_lenient_host = accepts_invalid_certs(&url, route, &crate::get_config().routing);
+    let mut tor_proxy = None;
+    let mut is_external_proxy = false;
     if !is_clearnet {
-        write_debug_about_msg("Sending this request using Tor", &id);
-        if !crate::is_tor_started() {
-            crate::tor::launch_tor();
-        }
-        if !crate::is_tor_ready() && !crate::tor::wait_for_tor(30, &crate::get_logfile_path()) {
-            return Ok(crate::messages::get_tor_failed_start_msg())
-        }
-    }
-
-    let mut builder = reqwest::blocking::Client::builder().timeout(Some(Duration::from_secs(75)));
-    let mut cert_added = false;
-    if let Some(cert_str) = message.certificate {
-        if let Ok(cert_bytes) = base64::decode_config(&cert_str, base64::URL_SAFE) {
-            if let Ok(cert) = reqwest::Certificate::from_der(&cert_bytes) {
-                builder = builder.add_root_certificate(cert);
-                cert_added = true;
+        tor_proxy = match crate::proxy::get_external_proxy() {
+            Some(proxy) => {
+                write_debug_about_msg(format!("Sending this request using Tor proxy {}:{}", &proxy.host, proxy.port), &id);
+                is_external_proxy = true;
+                Some(proxy)
+            },
+            None => match get_embedded_tor_proxy(&id) {
+                Ok(proxy) => Some(proxy),
+                Err(res) => return Ok(res),
             }
-        } else if let Ok(cert_bytes) = base64::decode(&cert_str) {
-            if let Ok(cert) = reqwest::Certificate::from_der(&cert_bytes) {
-                builder = builder.add_root_certificate(cert);
-                cert_added = true;
-            }
-        }
-        if !cert_added {
-            if let Ok(cert) = reqwest::Certificate::from_pem(cert_str.as_bytes()) {
-                builder = builder.add_root_certificate(cert);
-                cert_added = true;
-            }
-        }
+        };
     }
-    if cert_added {
+
+    let timeout = crate::get_config().timeouts.get_request();
+    let certificate = message.certificate.as_deref().and_then(NodeCertificate::parse);
+    if certificate.is_some() {
         write_debug_about_msg("Custom certificate has been set for this request", &id);
     }
-    if !is_clearnet && !cert_added {
-        builder = builder.danger_accept_invalid_certs(true);
-    }
-    if !is_clearnet {
-        let proxy = reqwest::Proxy::all(&format!("socks5h://127.0.0.1:{}", get_tor_port()))?.basic_auth(&get_tor_username(), &get_tor_password());
-        builder = builder.proxy(proxy);
-    }
-
-    let client = builder.build()?;
+    let accept_invalid_certs = is_lenient_host && certificate.is_none();
+    let client = get_client(ClientOptions {
+        proxy: tor_proxy,
+        certificate,
+        accept_invalid_certs,
+        timeout,
+    })?;
 
     let method = match message.method.as_str() {
         "GET" => reqwest::Method::GET,
@@ -104,13 +97,7 @@ pub fn get_response(message: ReqMessage) -> Result<ResMessage, ReqError> {
         }
     }
 
-    let res = client.request(method, url).headers(headers).body(message.body.unwrap_or_default()).send()?;
-    let status = res.status();
-    let mut res_headers: HashMap<String, String> = HashMap::new();
-    for (header_name, header_value) in res.headers().into_iter() {
-        res_headers.insert(header_name.to_string(), header_value.to_str().unwrap_or("[can not be converted into string]").to_string());
-    }
-    let body = res.text()?;
+    let (status, res_headers, body) = send_request(&client, method, url, headers, message.body, is_external_proxy)?;
     let length = body.len();
     if is_debug_mode() {
         write_debug_about_msg(format!("server response status: {}, length: {} response: {:#?}", &status, &length, &body), &id);
@@ -121,12 +108,127 @@ pub fn get_response(message: ReqMessage) -> Result<ResMessage, ReqError> {
 
     Ok(ResMessage {
         id: message.id,
-        status: status.into(),
+        status,
         body,
         headers: res_headers
     })
 }
 
+/// Clients are reused by the requests with the same options: a blocking client runs its own thread,
+/// and keeps the connections to the nodes alive.
+static CLIENTS: Mutex<Vec<(ClientOptions, reqwest::blocking::Client)>> = Mutex::new(vec![]);
+/// The oldest client is dropped beyond that, e.g. after the certificates of many nodes
+const MAX_CLIENTS: usize = 16;
+
+#[derive(PartialEq)]
+struct ClientOptions {
+    proxy: Option<ProxyConfig>,
+    certificate: Option<NodeCertificate>,
+    accept_invalid_certs: bool,
+    timeout: Duration,
+}
+
+fn get_client(options: ClientOptions) -> Result<reqwest::blocking::Client, ReqError> {
+    let mut clients = CLIENTS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((_, client)) = clients.iter().find(|(o, _)| *o == options) {
+        return Ok(client.clone());
+    }
+    let mut builder = reqwest::blocking::Client::builder().timeout(Some(options.timeout));
+    if let Some(cert) = &options.certificate {
+        builder = builder.add_root_certificate(cert.get_reqwest_certificate()?);
+    }
+    if options.accept_invalid_certs {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    if let Some(proxy) = &options.proxy {
+        builder = builder.proxy(proxy.get_reqwest_proxy()?);
+    }
+    let client = builder.build()?;
+    if clients.len() >= MAX_CLIENTS {
+        clients.remove(0);
+    }
+    clients.push((options, client.clone()));
+    Ok(client)
+}
+
+fn send_request(client: &reqwest::blocking::Client, method: reqwest::Method, url: reqwest::Url, headers: HeaderMap, body: Option<String>, is_external_proxy: bool) -> Result<(u16, HashMap<String, String>, String), ReqError> {
+    let res = match client.request(method, url).headers(headers).body(body.unwrap_or_default()).send() {
+        Ok(res) => res,
+        Err(err) => {
+            // the detected Tor may have been stopped, it is looked for again with the next request
+            if is_external_proxy && err.is_connect() {
+                crate::proxy::forget_system_tor();
+            }
+            return Err(err.into());
+        }
+    };
+    let status = res.status();
+    let mut res_headers: HashMap<String, String> = HashMap::new();
+    for (header_name, header_value) in res.headers().into_iter() {
+        res_headers.insert(header_name.to_string(), header_value.to_str().unwrap_or("[can not be converted into string]").to_string());
+    }
+    let body = res.text()?;
+    Ok((status.as_u16(), res_headers, body))
+}
+
+/// Custom root certificate of the node: base64 (URL-safe or standard) encoded DER, or PEM
+#[derive(Clone, PartialEq)]
+pub struct NodeCertificate {
+    pub bytes: Vec<u8>,
+    pub is_pem: bool,
+}
+
+impl NodeCertificate {
+    pub fn parse(cert_str: &str) -> Option<NodeCertificate> {
+        let der = base64::decode_config(cert_str, base64::URL_SAFE).or_else(|_| base64::decode(cert_str));
+        if let Ok(bytes) = der {
+            if reqwest::Certificate::from_der(&bytes).is_ok() {
+                return Some(NodeCertificate { bytes, is_pem: false });
+            }
+        }
+        match reqwest::Certificate::from_pem(cert_str.as_bytes()) {
+            Ok(_) => Some(NodeCertificate { bytes: cert_str.as_bytes().to_vec(), is_pem: true }),
+            Err(_) => None,
+        }
+    }
+
+    fn get_reqwest_certificate(&self) -> reqwest::Result<reqwest::Certificate> {
+        match self.is_pem {
+            true => reqwest::Certificate::from_pem(&self.bytes),
+            false => reqwest::Certificate::from_der(&self.bytes),
+        }
+    }
+}
+
+#[cfg(feature = "embedded-tor")]
+fn get_embedded_tor_proxy(id: &str) -> Result<ProxyConfig, ResMessage> {
+    write_debug_about_msg("Sending this request using Tor", id);
+    if !crate::is_tor_started() {
+        crate::tor::launch_tor();
+    }
+    if !crate::is_tor_ready() {
+        if let Err(err) = crate::tor::wait_for_tor(crate::get_config().timeouts.get_tor_bootstrap()) {
+            write_debug_about_msg(format!("Tor is not ready: {}", err), id);
+            return Err(crate::messages::get_tor_failed_start_msg());
+        }
+    }
+    // the port of the relay when Tor listens on a unix socket
+    Ok(ProxyConfig {
+        host: String::from("127.0.0.1"),
+        port: get_tor_port(),
+        username: Some(get_tor_username()),
+        password: Some(get_tor_password()),
+    })
+}
+
+#[cfg(not(feature = "embedded-tor"))]
+fn get_embedded_tor_proxy(id: &str) -> Result<ProxyConfig, ResMessage> {
+    write_debug_about_msg("Tor is not available in this build and no Tor proxy is configured", id);
+    // a Tor started since then is found with the next request
+    crate::proxy::forget_system_tor();
+    Err(crate::messages::get_tor_unavailable_msg())
+}
+
 fn write_debug_about_msg<T: Display>(dbg: T, msg_id: &str) {
     write_debug(format!("[{}]\t {}", msg_id, dbg));
 }
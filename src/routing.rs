@@ -0,0 +1,131 @@
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Route {
+    Tor,
+    Direct,
+    /// `.onion` through Tor, the rest according to the routing rules
+    #[default]
+    Auto,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RoutingConfig {
+    /// Route of the hosts which don't match any rule, `tor` sends all clearnet requests through Tor
+    pub default_route: Route,
+    /// Domain suffixes sent through Tor with the `auto` route
+    pub tor_domains: Vec<String>,
+    /// Domain suffixes sent directly with the `auto` route
+    pub direct_domains: Vec<String>,
+    /// Networks (CIDR) sent directly with the `auto` route
+    pub direct_networks: Vec<String>,
+}
+
+impl Default for RoutingConfig {
+    fn default() -> RoutingConfig {
+        RoutingConfig {
+            default_route: Route::Auto,
+            tor_domains: vec![],
+            // mDNS names of LAN nodes
+            direct_domains: vec![String::from(".local")],
+            direct_networks: [
+                "127.0.0.0/8", "10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "169.254.0.0/16",
+                // CGNAT, used by Tailscale
+                "100.64.0.0/10",
+                "::1/128", "fc00::/7", "fe80::/10",
+            ].iter().map(|n| n.to_string()).collect(),
+        }
+    }
+}
+
+pub fn is_onion_host(host: &str) -> bool {
+    host.trim_end_matches('.').ends_with(".onion")
+}
+
+/// Decides whether the request goes through Tor (`Route::Tor`) or directly (`Route::Direct`).
+pub fn get_route(url: &reqwest::Url, requested: Option<Route>, config: &RoutingConfig) -> Result<Route, String> {
+    let host = url.host_str().unwrap_or_default().to_lowercase();
+    let is_onion = is_onion_host(&host);
+    match requested.unwrap_or(Route::Auto) {
+        Route::Tor => return Ok(Route::Tor),
+        Route::Direct if is_onion => return Err(format!("{} can not be reached without Tor", host)),
+        Route::Direct => return Ok(Route::Direct),
+        Route::Auto => {},
+    }
+    if is_onion || matches_domain(&host, &config.tor_domains) {
+        return Ok(Route::Tor);
+    }
+    if matches_domain(&host, &config.direct_domains) {
+        return Ok(Route::Direct);
+    }
+    if let Some(ip) = get_ip(&host) {
+        if config.direct_networks.iter().any(|network| is_in_network(&ip, network)) {
+            return Ok(Route::Direct);
+        }
+        // nodes reached by a public IP went through Tor before the routing rules
+        if config.default_route == Route::Auto {
+            return Ok(Route::Tor);
+        }
+    }
+    match config.default_route {
+        Route::Tor => Ok(Route::Tor),
+        _ => Ok(Route::Direct),
+    }
+}
+
+/// Self-signed certificates are accepted from onion services, nodes reached by IP through Tor
+/// and the hosts matching the direct rules (LAN nodes), as `route` was chosen by `get_route`.
+pub fn accepts_invalid_certs(url: &reqwest::Url, route: Route, config: &RoutingConfig) -> bool {
+    let host = url.host_str().unwrap_or_default().to_lowercase();
+    if is_onion_host(&host) {
+        return true;
+    }
+    match (get_ip(&host), route) {
+        (Some(_), Route::Tor) => true,
+        (Some(ip), _) => config.direct_networks.iter().any(|network| is_in_network(&ip, network)),
+        (None, Route::Direct) => matches_domain(&host, &config.direct_domains),
+        (None, _) => false,
+    }
+}
+
+fn get_ip(host: &str) -> Option<IpAddr> {
+    host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok()
+}
+
+/// `.example.com` matches `example.com` and its subdomains, as `example.com` does.
+fn matches_domain(host: &str, suffixes: &[String]) -> bool {
+    let host = host.trim_end_matches('.');
+    suffixes.iter().any(|suffix| {
+        let suffix = suffix.trim_start_matches('.').trim_end_matches('.').to_lowercase();
+        !suffix.is_empty() && (host == suffix || host.ends_with(&format!(".{}", suffix)))
+    })
+}
+
+/// `192.168.1.10` is in `192.168.0.0/16`
+pub fn is_in_network(ip: &IpAddr, network: &str) -> bool {
+    let (addr, prefix) = match network.split_once('/') {
+        Some((addr, prefix)) => (addr, prefix.parse::<u32>().ok()),
+        None => (network, None),
+    };
+    let addr = match addr.trim().parse::<IpAddr>() {
+        Ok(a) => a,
+        Err(_) => return false,
+    };
+    match (ip, addr) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let prefix = prefix.unwrap_or(32).min(32);
+            let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix) };
+            u32::from(*ip) & mask == u32::from(net) & mask
+        },
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let prefix = prefix.unwrap_or(128).min(128);
+            let mask = if prefix == 0 { 0 } else { u128::MAX << (128 - prefix) };
+            u128::from(*ip) & mask == u128::from(net) & mask
+        },
+        _ => false,
+    }
+}
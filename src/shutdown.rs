@@ -0,0 +1,89 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::write_debug_to;
+
+/// How long in-flight requests and Tor get to finish before the process exits anyway
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Paths of the instance, taken when the shutdown is set up.
+pub struct ShutdownContext {
+    pub log_file: String,
+    #[cfg(feature = "embedded-tor")]
+    pub tor_dir: String,
+}
+
+impl ShutdownContext {
+    pub fn current() -> ShutdownContext {
+        ShutdownContext {
+            log_file: crate::get_logfile_path(),
+            #[cfg(feature = "embedded-tor")]
+            tor_dir: crate::get_tor_dir_path(),
+        }
+    }
+}
+
+/// Marks a request being handled, new requests are refused once the shutdown has started.
+pub struct InFlight;
+
+impl InFlight {
+    pub fn start() -> Option<InFlight> {
+        // counted before the check, so the shutdown either waits for it or it is refused
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlight;
+        match is_shutting_down() {
+            true => None,
+            false => Some(guard),
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Stops accepting messages, waits for in-flight requests, stops Tor, releases the lock file and exits.
+/// Used on the end of input from the browser, SIGTERM and the end of the daemon.
+pub fn shutdown(code: i32, ctx: &ShutdownContext) -> ! {
+    if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+        // the first caller exits the process
+        loop {
+            thread::park();
+        }
+    }
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    write_debug_to("Shutting down", &ctx.log_file, crate::is_debug_mode());
+    if !wait_for_requests(deadline) {
+        write_debug_to(format!("{} request(s) cancelled", IN_FLIGHT.load(Ordering::SeqCst)), &ctx.log_file, crate::is_debug_mode());
+    }
+    #[cfg(feature = "embedded-tor")]
+    if let Err(err) = crate::tor::stop_tor(&ctx.tor_dir, deadline) {
+        write_debug_to(format!("Can not stop Tor: {}", err), &ctx.log_file, crate::is_debug_mode());
+    }
+    let _ = std::io::stdout().flush();
+    write_debug_to(format!("Exit with code {}", code), &ctx.log_file, crate::is_debug_mode());
+    crate::exit(code);
+    unreachable!()
+}
+
+/// Waits until there are no in-flight requests, false if some are left at the deadline.
+pub fn wait_for_requests(deadline: Instant) -> bool {
+    while IN_FLIGHT.load(Ordering::SeqCst) > 0 {
+        if Instant::now() > deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    true
+}
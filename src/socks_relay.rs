@@ -0,0 +1,114 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+use std::thread;
+
+use crate::{get_tor_password, get_tor_username, write_debug_to};
+use crate::daemon::is_same_token;
+
+/// Relays of this process by unix socket, Tor may be launched again with the same data folder
+static RELAYS: Mutex<Vec<(String, u16)>> = Mutex::new(vec![]);
+
+/// Port of the relay to the unix socket, started with the first call.
+pub fn get_port(socket: &str) -> Result<u16, String> {
+    let mut relays = RELAYS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((_, port)) = relays.iter().find(|(s, _)| s == socket) {
+        return Ok(*port);
+    }
+    let port = start(socket)?;
+    relays.push((socket.to_string(), port));
+    Ok(port)
+}
+
+/// SOCKS5 proxy on a local port in front of the unix socket of the embedded Tor, reqwest can only use a TCP proxy.
+/// Clients must authenticate with the Tor username and password (RFC 1929), which are passed on to Tor.
+pub fn start(socket: &str) -> Result<u16, String> {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).map_err(|e| format!("Can not open SOCKS relay port: {}", e))?;
+    let port = listener.local_addr().map_err(|e| format!("Can not get SOCKS relay port: {}", e))?.port();
+    let socket = socket.to_string();
+    let log_file = crate::get_logfile_path();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let (socket, log_file) = (socket.clone(), log_file.clone());
+            thread::spawn(move || {
+                if let Err(err) = relay(stream, &socket, &get_tor_username(), &get_tor_password()) {
+                    write_debug_to(format!("SOCKS relay error: {}", err), &log_file, crate::is_debug_mode());
+                }
+            });
+        }
+    });
+    Ok(port)
+}
+
+fn relay(mut client: TcpStream, socket: &str, username: &str, password: &str) -> io::Result<()> {
+    let mut greeting = [0u8; 2];
+    client.read_exact(&mut greeting)?;
+    let mut methods = vec![0u8; greeting[1] as usize];
+    client.read_exact(&mut methods)?;
+    if greeting[0] != 0x05 || !methods.contains(&0x02) {
+        client.write_all(&[0x05, 0xff])?;
+        return Err(invalid_data("client doesn't offer username and password"));
+    }
+    client.write_all(&[0x05, 0x02])?;
+    let (client_username, client_password) = read_credentials(&mut client)?;
+    // both are compared, the time doesn't tell which one is wrong
+    let is_authorized = is_same_token(&client_username, username.as_bytes()) & is_same_token(&client_password, password.as_bytes());
+    if !is_authorized {
+        client.write_all(&[0x01, 0x01])?;
+        return Err(invalid_data("invalid username or password"));
+    }
+    client.write_all(&[0x01, 0x00])?;
+
+    let mut tor = UnixStream::connect(socket)?;
+    tor.write_all(&[0x05, 0x01, 0x02])?;
+    let mut reply = [0u8; 2];
+    tor.read_exact(&mut reply)?;
+    if reply != [0x05, 0x02] {
+        return Err(invalid_data("Tor doesn't accept username and password"));
+    }
+    tor.write_all(&get_credentials_message(username, password))?;
+    tor.read_exact(&mut reply)?;
+    if reply[1] != 0x00 {
+        return Err(invalid_data("Tor refused the username and password"));
+    }
+
+    // the CONNECT request, the reply of Tor and the connection itself are copied as they are
+    let (mut client_read, mut tor_write) = (client.try_clone()?, tor.try_clone()?);
+    let upload = thread::spawn(move || {
+        let _ = io::copy(&mut client_read, &mut tor_write);
+        let _ = tor_write.shutdown(Shutdown::Write);
+    });
+    let _ = io::copy(&mut tor, &mut client);
+    let _ = client.shutdown(Shutdown::Write);
+    let _ = upload.join();
+    Ok(())
+}
+
+/// RFC 1929 request: version 1, username and password with their lengths
+fn read_credentials(client: &mut TcpStream) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    client.read_exact(&mut header)?;
+    if header[0] != 0x01 {
+        return Err(invalid_data("unknown authentication version"));
+    }
+    let mut username = vec![0u8; header[1] as usize];
+    client.read_exact(&mut username)?;
+    let mut length = [0u8; 1];
+    client.read_exact(&mut length)?;
+    let mut password = vec![0u8; length[0] as usize];
+    client.read_exact(&mut password)?;
+    Ok((username, password))
+}
+
+fn get_credentials_message(username: &str, password: &str) -> Vec<u8> {
+    let mut message = vec![0x01, username.len() as u8];
+    message.extend_from_slice(username.as_bytes());
+    message.push(password.len() as u8);
+    message.extend_from_slice(password.as_bytes());
+    message
+}
+
+fn invalid_data(err: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fs;
 
 use serial_test::serial;
@@ -7,6 +8,7 @@ use crate::messages::ReqMessage;
 
 #[test]
 #[serial]
+#[ignore = "needs the network"]
 pub fn test_clearnet_request() {
     crate::prepare_log_file();
     let mut msg: ReqMessage = Default::default();
@@ -21,6 +23,7 @@ pub fn test_clearnet_request() {
 
 #[test]
 #[serial]
+#[ignore = "needs the network"]
 pub fn test_logging_messages_only_in_debug_mode() {
     crate::prepare_log_file();
 
@@ -52,10 +55,13 @@ pub fn test_logging_messages_only_in_debug_mode() {
         },
         Err(e) => panic!("e: {:#?}", e)
     }
+    crate::set_debug_mode(false);
 }
 
+#[cfg(feature = "embedded-tor")]
 #[test]
 #[serial]
+#[ignore = "needs the network"]
 pub fn test_tor_request() {
     crate::prepare_log_file();
     let mut msg: ReqMessage = Default::default();
@@ -76,6 +82,150 @@ pub fn test_tor_request() {
     }
 }
 
+// Offline: a local HTTPS server with a self-signed certificate, and a SOCKS5 stand-in in place of Tor
+
+#[test]
+#[serial]
+pub fn test_offline_clearnet_request() {
+    use crate::test_servers::EchoServer;
+
+    crate::reset_settings();
+    crate::prepare_log_file();
+    let server = EchoServer::start();
+    let msg = ReqMessage {
+        id: "20".to_string(),
+        url: format!("https://127.0.0.1:{}/v1/invoices", server.get_port()),
+        method: String::from("POST"),
+        body: Some(String::from("{\"value\":1000}")),
+        params: Some(HashMap::from([(String::from("pending_only"), String::from("true"))])),
+        headers: Some(HashMap::from([(String::from("Grpc-Metadata-macaroon"), String::from("0201036c6e64"))])),
+        ..Default::default()
+    };
+    let res = crate::requests::get_response(msg).unwrap();
+    assert_eq!(res.id, "20");
+    assert_eq!(res.status, 200);
+    assert_eq!(res.headers["x-test-server"], "echo");
+    let echo: serde_json::Value = serde_json::from_str(&res.body).unwrap();
+    assert_eq!(echo["method"], "POST");
+    assert_eq!(echo["path"], "/v1/invoices?pending_only=true");
+    assert_eq!(echo["headers"]["grpc-metadata-macaroon"], "0201036c6e64");
+    assert_eq!(echo["body"], "{\"value\":1000}");
+
+    // the status of the node is passed as is
+    let msg = ReqMessage { id: "21".to_string(), url: format!("https://127.0.0.1:{}/missing", server.get_port()), ..Default::default() };
+    assert_eq!(crate::requests::get_response(msg).unwrap().status, 404);
+}
+
+#[test]
+#[serial]
+pub fn test_offline_certificates() {
+    use crate::test_servers::{CERT_PEM, EchoServer};
+
+    crate::reset_settings();
+    crate::prepare_log_file();
+    let server = EchoServer::start();
+    let url = format!("https://localhost:{}/v1/getinfo", server.get_port());
+    // a self-signed certificate is only accepted for IP and onion addresses without the certificate of the node
+    let msg = ReqMessage { id: "22".to_string(), url: url.clone(), ..Default::default() };
+    assert!(crate::requests::get_response(msg).is_err());
+
+    let msg = ReqMessage { id: "23".to_string(), url: url.clone(), certificate: Some(CERT_PEM.to_string()), ..Default::default() };
+    assert_eq!(crate::requests::get_response(msg).unwrap().status, 200);
+
+    // base64 URL-safe DER, like the certificate in the lndconnect URL
+    let pem_body: String = CERT_PEM.lines().filter(|l| !l.starts_with("-----")).collect();
+    let der = base64::encode_config(base64::decode(pem_body).unwrap(), base64::URL_SAFE_NO_PAD);
+    let msg = ReqMessage { id: "24".to_string(), url, certificate: Some(der), ..Default::default() };
+    assert_eq!(crate::requests::get_response(msg).unwrap().status, 200);
+}
+
+#[test]
+#[serial]
+pub fn test_offline_onion_routing() {
+    use crate::config::ProxyConfig;
+    use crate::routing::Route;
+    use crate::test_servers::{CERT_PEM, EchoServer, SocksStandIn};
+
+    crate::reset_settings();
+    crate::prepare_log_file();
+    let server = EchoServer::start();
+    let socks = SocksStandIn::start(server.addr);
+    let mut config = crate::get_config();
+    config.tor.proxy = Some(ProxyConfig { host: String::from("127.0.0.1"), port: socks.addr.port(), ..Default::default() });
+    crate::set_config(config);
+
+    // the onion address is resolved by the proxy
+    let onion = "wqskhzt3oiz76dgqbqh27j3qw5aeaui3jxyexzuwxqa5czzo24i3z3ad.onion";
+    let msg = ReqMessage { id: "25".to_string(), url: format!("https://{}:8080/v1/getinfo", onion), ..Default::default() };
+    let res = crate::requests::get_response(msg).unwrap();
+    assert_eq!(res.status, 200);
+    assert_eq!(socks.get_targets(), vec![format!("{}:8080", onion)]);
+
+    let url = format!("https://localhost:{}/v1/getinfo", server.get_port());
+    let msg = ReqMessage { id: "26".to_string(), url: url.clone(), certificate: Some(CERT_PEM.to_string()), route: Some(Route::Tor), ..Default::default() };
+    assert_eq!(crate::requests::get_response(msg).unwrap().status, 200);
+    assert_eq!(socks.get_targets().last(), Some(&format!("localhost:{}", server.get_port())));
+
+    let msg = ReqMessage { id: "27".to_string(), url, certificate: Some(CERT_PEM.to_string()), ..Default::default() };
+    assert_eq!(crate::requests::get_response(msg).unwrap().status, 200);
+    assert_eq!(socks.get_targets().len(), 2);
+}
+
+#[cfg(feature = "embedded-tor")]
+#[test]
+#[serial]
+pub fn test_offline_embedded_tor() {
+    use crate::test_servers::{EchoServer, SocksStandIn};
+
+    crate::reset_settings();
+    crate::prepare_log_file();
+    let server = EchoServer::start();
+    let socks = SocksStandIn::start(server.addr);
+    let mut config = crate::get_config();
+    config.tor.proxy = None;
+    crate::set_config(config);
+    // the stand-in takes the place of the running embedded Tor
+    crate::set_tor_is_started(true);
+    crate::set_tor_is_ready(true);
+    crate::set_tor_port(socks.addr.port());
+
+    let onion = "wqskhzt3oiz76dgqbqh27j3qw5aeaui3jxyexzuwxqa5czzo24i3z3ad.onion";
+    let msg = ReqMessage { id: "28".to_string(), url: format!("https://{}:8080/v1/getinfo", onion), ..Default::default() };
+    assert_eq!(crate::requests::get_response(msg).unwrap().status, 200);
+    assert_eq!(socks.get_targets(), vec![format!("{}:8080", onion)]);
+    // Tor isolates the streams of alby by the username and password
+    assert_eq!(socks.get_credentials(), vec![(crate::get_tor_username(), crate::get_tor_password())]);
+
+    crate::set_tor_port(0);
+    crate::set_tor_is_ready(false);
+    crate::set_tor_is_started(false);
+}
+
+#[test]
+#[serial]
+pub fn test_offline_error_mapping() {
+    use crate::messages::handler;
+
+    crate::reset_settings();
+    crate::prepare_log_file();
+    let closed_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let messages = [
+        serde_json::json!({ "id": "28", "url": format!("https://127.0.0.1:{}", closed_port), "method": "GET" }),
+        serde_json::json!({ "id": "29", "url": "not a url", "method": "GET" }),
+        serde_json::json!({ "id": "30", "url": "https://wqskhzt3oiz76dgqbqh27j3qw5aeaui3jxyexzuwxqa5czzo24i3z3ad.onion", "method": "GET", "route": "direct" }),
+    ];
+    for msg in messages {
+        let id = msg["id"].as_str().unwrap().to_string();
+        let res = handler(msg).unwrap();
+        assert_eq!(res.id, id);
+        assert_eq!(res.status, 500);
+        assert_eq!(res.headers["X-Alby-Internal"], "true");
+        assert!(res.body.starts_with("Can not get response from resource"));
+    }
+    // the event loop answers with the error
+    assert!(handler(serde_json::json!({ "id": 31 })).is_err());
+}
+
 #[test]
 pub fn test_cli() {
     let opts = crate::cli::get_cli_options(crate::cli::get_args_from_string("command string --log_file=LF -t=TD"));
@@ -86,6 +236,180 @@ pub fn test_cli() {
     assert_eq!(opts.tor_dir, None);
 }
 
+#[test]
+pub fn test_cli_validation() {
+    use crate::cli::{Command, get_args_from_string, try_get_cli_options};
+
+    // arguments passed by Firefox and Chrome
+    let opts = try_get_cli_options(get_args_from_string("alby /home/u/.mozilla/native-messaging-hosts/alby.json extension@getalby.com")).unwrap();
+    assert_eq!(opts.command, Command::Serve);
+    let opts = try_get_cli_options(get_args_from_string("alby chrome-extension://iokeahhehimjnekafflcihljlcjccdbe/ --parent-window=0")).unwrap();
+    assert_eq!(opts.command, Command::Serve);
+    let opts = try_get_cli_options(get_args_from_string("alby serve --tor-dir=/tmp/a=b -debug")).unwrap();
+    assert_eq!(opts.tor_dir, Some(String::from("/tmp/a=b")));
+    assert!(opts.debug_mode);
+
+    assert!(try_get_cli_options(get_args_from_string("alby --unknown-flag")).is_err());
+    assert!(try_get_cli_options(get_args_from_string("alby --socks-port=x")).is_err());
+    assert!(try_get_cli_options(get_args_from_string("alby --route=everywhere")).is_err());
+    assert!(try_get_cli_options(get_args_from_string("alby --proxy=127.0.0.1")).is_err());
+    let err = try_get_cli_options(get_args_from_string("alby --version")).err().unwrap();
+    assert_eq!(err.kind(), clap::ErrorKind::DisplayVersion);
+}
+
+#[test]
+pub fn test_install_manifests() {
+    use crate::cli::{Command, InstallOptions, UninstallOptions, get_args_from_string, try_get_cli_options};
+    use crate::install::{Browser, install_manifests, uninstall_manifests};
+
+    let home = std::env::temp_dir().join(format!("alby-test-install-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&home);
+    let exe = std::path::Path::new("/opt/alby/alby");
+    let mut opts = InstallOptions::default();
+    assert!(install_manifests(&home, exe, &opts).is_err());
+
+    // only the browsers which are found
+    fs::create_dir_all(home.join(".mozilla")).unwrap();
+    fs::create_dir_all(home.join(".config/BraveSoftware/Brave-Browser")).unwrap();
+    opts.dry_run = true;
+    let lines = install_manifests(&home, exe, &opts).unwrap();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("firefox: would create"));
+    assert!(!Browser::Firefox.get_manifest_path(&home).exists());
+
+    opts.dry_run = false;
+    opts.origins = vec![String::from("abcdefghijklmnop")];
+    install_manifests(&home, exe, &opts).unwrap();
+    let firefox: serde_json::Value = serde_json::from_str(&fs::read_to_string(home.join(".mozilla/native-messaging-hosts/alby.json")).unwrap()).unwrap();
+    assert_eq!(firefox["path"], "/opt/alby/alby");
+    assert_eq!(firefox["type"], "stdio");
+    assert_eq!(firefox["allowed_extensions"][0], "extension@getalby.com");
+    assert!(firefox.get("allowed_origins").is_none());
+    let brave: serde_json::Value = serde_json::from_str(&fs::read_to_string(Browser::Brave.get_manifest_path(&home)).unwrap()).unwrap();
+    assert_eq!(brave["allowed_origins"][0], "chrome-extension://abcdefghijklmnop/");
+    assert!(install_manifests(&home, exe, &opts).unwrap()[0].ends_with("is up to date"));
+    assert!(install_manifests(&home, std::path::Path::new("alby"), &opts).is_err());
+
+    let lines = uninstall_manifests(&home, &UninstallOptions { browsers: vec![], dry_run: true }).unwrap();
+    assert_eq!(lines.len(), 2);
+    assert!(Browser::Brave.get_manifest_path(&home).exists());
+    uninstall_manifests(&home, &Default::default()).unwrap();
+    assert!(!Browser::Firefox.get_manifest_path(&home).exists());
+    assert!(!Browser::Brave.get_manifest_path(&home).exists());
+    let _ = fs::remove_dir_all(&home);
+
+    let opts = try_get_cli_options(get_args_from_string("alby install --browser=chrome --browser=edge --dry-run")).unwrap();
+    assert_eq!(opts.command, Command::Install(InstallOptions { browsers: vec![Browser::Chrome, Browser::Edge], dry_run: true, ..Default::default() }));
+    assert!(try_get_cli_options(get_args_from_string("alby install --browser=safari")).is_err());
+}
+
+#[test]
+#[serial]
+pub fn test_doctor_checks() {
+    use crate::doctor::{check_instance, check_manifest};
+    use crate::install::{Browser, get_manifest};
+
+    crate::reset_settings();
+    let exe = std::env::current_exe().unwrap();
+    let manifest = |browser| serde_json::to_string(&get_manifest(browser, &exe, &[], &[])).unwrap();
+    let expected = |browser| get_manifest(browser, std::path::Path::new(""), &[], &[]);
+    assert_eq!(check_manifest(&manifest(Browser::Firefox), &expected(Browser::Firefox)), Ok(exe.to_string_lossy().to_string()));
+    assert!(check_manifest(&manifest(Browser::Vivaldi), &expected(Browser::Vivaldi)).is_ok());
+    // the origin of Chrome doesn't allow Firefox
+    assert!(check_manifest(&manifest(Browser::Chrome), &expected(Browser::Firefox)).unwrap_err().contains("allowed_extensions"));
+    let other = get_manifest(Browser::Chrome, &exe, &[], &[String::from("abcdefghijklmnop")]);
+    assert!(check_manifest(&serde_json::to_string(&other).unwrap(), &expected(Browser::Chrome)).unwrap_err().contains("allowed_origins"));
+    // the origin of the config file
+    let configured = get_manifest(Browser::Chrome, &exe, &[], &[String::from("abcdefghijklmnop")]);
+    assert!(check_manifest(&serde_json::to_string(&other).unwrap(), &configured).is_ok());
+    let missing = get_manifest(Browser::Chrome, std::path::Path::new("/nonexistent/alby"), &[], &[]);
+    assert!(check_manifest(&serde_json::to_string(&missing).unwrap(), &expected(Browser::Chrome)).unwrap_err().contains("not executable"));
+    let relative = manifest(Browser::Firefox).replace(&*exe.to_string_lossy(), "./target/release/alby");
+    assert!(check_manifest(&relative, &expected(Browser::Firefox)).unwrap_err().contains("not absolute"));
+    assert!(check_manifest("{", &expected(Browser::Firefox)).is_err());
+
+    let path = crate::get_lock_file_path();
+    let lock = crate::create_lock_file();
+    assert!(lock.is_some());
+    let check = check_instance(&path);
+    assert!(check.ok);
+    assert!(check.detail.unwrap().starts_with(&format!("running, PID and start: {} ", std::process::id())));
+    drop(lock);
+    assert_eq!(check_instance(&path).detail, Some(String::from("not running")));
+    // doctor doesn't keep the lock
+    assert!(crate::create_lock_file().is_some());
+
+    // the instances in the other slots are found too, the missing lock files are not created
+    let paths = crate::doctor::get_lock_file_paths();
+    assert_eq!(paths.len(), crate::MAX_INSTANCES as usize);
+    assert_eq!(paths[2], format!("{}.process", crate::paths::get_profile_path(&crate::get_logfile_path(), "3")));
+    let _ = fs::remove_file(&paths[7]);
+    let _ = fs::remove_file(format!("{}.owner", &paths[2]));
+    let slot = fs::File::create(&paths[2]).unwrap();
+    fs2::FileExt::lock_exclusive(&slot).unwrap();
+    let report = crate::doctor::get_report();
+    assert!(report.instance.ok);
+    assert_eq!(report.instance.detail, Some(String::from("running, unknown")));
+    assert!(!std::path::Path::new(&paths[7]).exists());
+    drop(slot);
+    let _ = fs::remove_file(&paths[2]);
+    assert_eq!(crate::doctor::get_report().instance.detail, Some(String::from("not running")));
+}
+
+#[test]
+pub fn test_request_options() {
+    use crate::cli::{Command, get_args_from_string, try_get_cli_options};
+
+    let opts = try_get_cli_options(get_args_from_string("alby request --url=https://example.onion/v1/getinfo --method=post -H Grpc-Metadata-macaroon:abc --header=Accept:application/json --body={}")).unwrap();
+    let args = match opts.command {
+        Command::Request(args) => args,
+        _ => panic!("not a request"),
+    };
+    let msg = args.get_message().unwrap();
+    assert_eq!(msg.id, "cli");
+    assert_eq!(msg.method, "POST");
+    assert_eq!(msg.url, "https://example.onion/v1/getinfo");
+    assert_eq!(msg.body, Some(String::from("{}")));
+    let headers = msg.headers.unwrap();
+    assert_eq!(headers["Grpc-Metadata-macaroon"], "abc");
+    assert_eq!(headers["Accept"], "application/json");
+    assert!(msg.certificate.is_none());
+
+    assert!(try_get_cli_options(get_args_from_string("alby request")).is_err());
+    assert!(try_get_cli_options(get_args_from_string("alby request --url=https://a --header=novalue")).is_err());
+    let opts = try_get_cli_options(get_args_from_string("alby request --url=https://a --cert=notacert")).unwrap();
+    match opts.command {
+        Command::Request(args) => assert!(args.get_message().is_err()),
+        _ => panic!("not a request"),
+    }
+}
+
+#[test]
+pub fn test_framing() {
+    use crate::framing::{decode_all, encode, parse_session, read_frame};
+
+    let msg = serde_json::json!({ "id": "1", "url": "https://example.com", "method": "GET" });
+    let frame = encode(&msg).unwrap();
+    assert_eq!(&frame[..4], &(frame.len() as u32 - 4).to_ne_bytes());
+    let mut frames = frame.clone();
+    frames.extend(encode(&serde_json::json!({ "id": "2" })).unwrap());
+    let values = decode_all(&frames).unwrap();
+    assert_eq!(values.len(), 2);
+    assert_eq!(values[0], msg);
+    assert_eq!(values[1]["id"], "2");
+    assert!(read_frame(&mut &b""[..]).unwrap().is_none());
+    assert!(read_frame(&mut &frame[..2]).is_err());
+    assert!(read_frame(&mut &frame[..frame.len() - 1]).is_err());
+    assert!(read_frame(&mut &u32::MAX.to_ne_bytes()[..]).is_err());
+
+    // captured sessions
+    assert_eq!(parse_session(&frames).unwrap().len(), 2);
+    assert_eq!(parse_session(b"{\"id\": \"1\"}\n{\"id\": \"2\"}\n").unwrap()[1]["id"], "2");
+    assert_eq!(parse_session(b"[{\"id\": \"1\"}, {\"id\": \"2\"}]").unwrap().len(), 2);
+    assert!(parse_session(b"not json").is_err());
+}
+
+#[cfg(feature = "embedded-tor")]
 #[test]
 #[serial]
 #[ignore]
@@ -111,8 +435,10 @@ pub fn test_der_cert() {
 pub fn test_create_lock_file() {
     use crate::{create_lock_file, get_lock_file_path};
 
+    crate::reset_settings();
     let path = get_lock_file_path();
     assert!(!path.is_empty());
+    // left by a crashed process, nobody holds the lock
     let _ = fs::write(&path, "12345");
     // scope for RAII
     {
@@ -120,12 +446,671 @@ pub fn test_create_lock_file() {
         assert!(lock1.is_some());
         let lock2 = create_lock_file();
         assert!(lock2.is_none());
+        let owner = fs::read_to_string(format!("{}.owner", &path)).unwrap();
+        assert!(owner.starts_with(&format!("{} ", std::process::id())));
     }
-    // lock1 and lock2 should call their "Drop" and remove their files
+    // the lock is released with the file handle of lock1
     let lock3 = create_lock_file();
     assert!(lock3.is_some());
-    // non-parseable PID is a reason to remove lock 
+    drop(lock3);
+    // content of the file doesn't matter
     let _ = fs::write(&path, "---{!}---");
     let lock4 = create_lock_file();
     assert!(lock4.is_some());
 }
+
+/// Started by `test_take_over_instance` in a child process, which must be this executable
+#[test]
+#[ignore = "holds the lock file of a test"]
+pub fn hold_lock_file() {
+    if let Ok(args) = std::env::var("ALBY_TEST_HOLD_LOCK") {
+        crate::apply_cli_options(&crate::cli::try_get_cli_options(crate::cli::get_args_from_string(&args)).unwrap());
+        let _lock = crate::create_lock_file().unwrap();
+        std::thread::sleep(std::time::Duration::from_secs(30));
+    }
+}
+
+#[test]
+#[serial]
+pub fn test_take_over_instance() {
+    use std::time::{Duration, Instant};
+
+    crate::reset_settings();
+    let dir = std::env::temp_dir().join(format!("alby-test-takeover-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let args = format!("alby --config={} --log-file={}", dir.join("config.json").to_string_lossy(), dir.join("alby.log").to_string_lossy());
+    crate::apply_cli_options(&crate::cli::try_get_cli_options(crate::cli::get_args_from_string(&args)).unwrap());
+    let owner_file = format!("{}.owner", crate::get_lock_file_path());
+    let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "test::hold_lock_file", "--ignored"])
+        .env("ALBY_TEST_HOLD_LOCK", &args)
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while fs::read_to_string(&owner_file).unwrap_or_default().split_whitespace().next() != Some(&child.id().to_string()) {
+        assert!(Instant::now() < deadline, "the child process has not locked the file");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert!(crate::create_lock_file().is_none());
+
+    // the owner file names the holder, the lock file itself may not be readable
+    let lock = crate::take_over_instance();
+    assert!(lock.is_some());
+    assert!(!child.wait().unwrap().success());
+    assert!(fs::read_to_string(&owner_file).unwrap().starts_with(&format!("{} ", std::process::id())));
+    drop(lock);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[serial]
+pub fn test_instance_profiles() {
+    use crate::{get_lock_file_path, get_profile, get_tor_dir_path, lock_instance, MAX_INSTANCES};
+    use crate::paths::{get_profile_path, is_valid_profile};
+
+    crate::reset_settings();
+    assert_eq!(get_profile_path("/tmp/alby.log", "work"), "/tmp/alby-work.log");
+    assert_eq!(get_profile_path("/home/u/.local/share/alby/tor", "2"), "/home/u/.local/share/alby/tor-2");
+    assert!(is_valid_profile("work_2"));
+    assert!(!is_valid_profile("../tor"));
+    assert!(!is_valid_profile(""));
+
+    let log_file = get_logfile_path();
+    let tor_dir = get_tor_dir_path();
+    let _ = fs::remove_file(get_lock_file_path());
+    let _ = fs::remove_file(format!("{}.process", get_profile_path(&log_file, "2")));
+    let first = lock_instance(None, MAX_INSTANCES);
+    assert!(first.is_some());
+    assert_eq!(get_profile(), None);
+    // the second companion takes the next slot with its own files
+    let second = lock_instance(None, MAX_INSTANCES);
+    assert!(second.is_some());
+    assert_eq!(get_profile(), Some(String::from("2")));
+    assert_eq!(get_logfile_path(), get_profile_path(&log_file, "2"));
+    assert_eq!(get_tor_dir_path(), get_profile_path(&tor_dir, "2"));
+
+    // no free slot for a third companion, started with the default paths: those of the first instance are kept for a takeover
+    crate::reset_settings();
+    assert!(lock_instance(None, 2).is_none());
+    assert_eq!(get_profile(), None);
+    assert_eq!(get_logfile_path(), log_file);
+    let msg = crate::messages::get_instance_conflict_msg(String::from("7"), "Companion is already running");
+    assert_eq!(msg.status, 409);
+    assert_eq!(msg.headers.get("X-Alby-description").map(|d| d.as_str()), Some("Companion is already running"));
+}
+
+#[test]
+#[serial]
+pub fn test_daemon_shim_fallback() {
+    use crate::{get_lock_file_path, get_tor_dir_path, lock_instance, MAX_INSTANCES, with_profile_log_file};
+    use crate::paths::get_profile_path;
+
+    crate::reset_settings();
+    let log_file = get_logfile_path();
+    let tor_dir = get_tor_dir_path();
+    let _ = fs::remove_file(format!("{}.process", get_profile_path(&log_file, "work")));
+    let shim = with_profile_log_file(Some("work"), || {
+        assert_eq!(get_logfile_path(), get_profile_path(&log_file, "work"));
+        Err::<(), _>(String::from("Can not connect to the daemon"))
+    });
+    assert!(shim.is_err());
+    assert_eq!(get_logfile_path(), log_file);
+    // the same files as an instance of the profile started without the daemon
+    let lock = lock_instance(Some(String::from("work")), MAX_INSTANCES);
+    assert!(lock.is_some());
+    assert_eq!(get_logfile_path(), get_profile_path(&log_file, "work"));
+    assert_eq!(get_lock_file_path(), format!("{}.process", get_profile_path(&log_file, "work")));
+    assert_eq!(get_tor_dir_path(), get_profile_path(&tor_dir, "work"));
+}
+
+#[test]
+pub fn test_daemon_protocol() {
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use crate::daemon::{DaemonConnection, DaemonEndpoint, Job, serve_client, write_endpoint_file};
+
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let endpoint = DaemonEndpoint { port: listener.local_addr().unwrap().port(), token: String::from("secret"), pid: std::process::id() };
+    let (sender, receiver) = channel::<Job>();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let sender = sender.clone();
+            std::thread::spawn(move || serve_client(stream, "secret", sender));
+        }
+    });
+    // the worker of the daemon
+    std::thread::spawn(move || {
+        for (message, reply) in receiver {
+            let _ = reply.send(serde_json::json!({ "id": message["id"], "status": 200 }).to_string());
+        }
+    });
+
+    let path = std::env::temp_dir().join(format!("alby-daemon-test-{}.json", std::process::id())).to_string_lossy().to_string();
+    write_endpoint_file(&path, &endpoint).unwrap();
+    let mut connection = DaemonConnection::open(&path).expect("token is accepted");
+    let response = connection.send(&serde_json::json!({ "id": "42", "url": "https://getalby.com" })).unwrap();
+    assert_eq!(response["id"], "42");
+    assert_eq!(response["status"], 200);
+
+    write_endpoint_file(&path, &DaemonEndpoint { token: String::from("secre"), ..endpoint }).unwrap();
+    assert!(DaemonConnection::open(&path).is_err());
+    write_endpoint_file(&path, &DaemonEndpoint { token: String::from("wrong"), ..endpoint }).unwrap();
+    assert!(DaemonConnection::open(&path).is_err());
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+#[serial]
+pub fn test_daemon_concurrent_jobs() {
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use crate::daemon::spawn_job;
+
+    crate::reset_settings();
+    // a node which accepts the connection but never answers
+    let node = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://127.0.0.1:{}/v1/getinfo", node.local_addr().unwrap().port());
+    let (sender, slow_reply) = channel();
+    spawn_job((serde_json::json!({ "id": "1", "url": url, "method": "GET" }), sender));
+    // the message of another browser doesn't wait for it
+    let (sender, reply) = channel();
+    spawn_job((serde_json::json!({ "id": "2", "url": "", "method": "GET", "action": "getSettings" }), sender));
+    let response: serde_json::Value = serde_json::from_str(&reply.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap();
+    assert_eq!(response["id"], "2");
+    assert_eq!(response["status"], 200);
+    assert!(slow_reply.try_recv().is_err());
+    drop(node);
+}
+
+#[test]
+pub fn test_shutdown_waits_for_requests() {
+    use std::time::{Duration, Instant};
+    use crate::shutdown::{InFlight, wait_for_requests};
+
+    let request = InFlight::start().expect("requests are accepted before the shutdown");
+    assert!(!wait_for_requests(Instant::now() + Duration::from_millis(100)));
+    let finishing = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(100));
+        drop(request);
+    });
+    assert!(wait_for_requests(Instant::now() + Duration::from_secs(5)));
+    let _ = finishing.join();
+}
+
+#[test]
+pub fn test_watchdog_parent() {
+    use sysinfo::{System, SystemExt};
+    use crate::watchdog::{get_parent_pid, is_process_alive};
+
+    let mut sys = System::new();
+    let parent = get_parent_pid().expect("cargo runs the tests");
+    assert!(is_process_alive(&mut sys, parent));
+    assert!(is_process_alive(&mut sys, std::process::id()));
+}
+
+#[cfg(feature = "embedded-tor")]
+#[test]
+pub fn test_tor_bridges_config() {
+    use crate::tor::SocksListener;
+
+    let args = vec![
+        "alby", "--use-bridges",
+        "--bridge=obfs4 192.0.2.1:443 0123456789ABCDEF0123456789ABCDEF01234567 cert=AbCd+ef/gh iat-mode=0",
+        "--transport-plugin=obfs4:/usr/bin/obfs4proxy",
+    ];
+    let opts = crate::cli::get_cli_options(args.into_iter().map(|v| v.to_string()));
+    assert!(opts.use_bridges);
+    assert_eq!(opts.bridges, vec![String::from("obfs4 192.0.2.1:443 0123456789ABCDEF0123456789ABCDEF01234567 cert=AbCd+ef/gh iat-mode=0")]);
+
+    let config = crate::config::TorConfig {
+        use_bridges: opts.use_bridges,
+        bridges: opts.bridges,
+        transport_plugins: opts.transport_plugins,
+        ..Default::default()
+    };
+    assert_eq!(config.get_bridge_transports(), vec![String::from("obfs4")]);
+    let lines = crate::tor::get_torrc_lines(&config, "TD", &SocksListener::Port(19050));
+    assert!(lines.contains(&String::from("UseBridges 1")));
+    assert!(lines.contains(&String::from("Bridge obfs4 192.0.2.1:443 0123456789ABCDEF0123456789ABCDEF01234567 cert=AbCd+ef/gh iat-mode=0")));
+    assert!(lines.contains(&String::from("ClientTransportPlugin obfs4 exec /usr/bin/obfs4proxy")));
+
+    let lines = crate::tor::get_torrc_lines(&Default::default(), "TD", &SocksListener::Port(0));
+    assert!(lines.contains(&String::from("SocksPort auto")));
+    assert!(!lines.iter().any(|l| l.starts_with("UseBridges") || l.starts_with("Bridge")));
+    assert!(lines.contains(&String::from("CookieAuthentication 1")));
+}
+
+#[test]
+#[serial]
+pub fn test_config_file() {
+    use crate::cli::{get_args_from_string, try_get_cli_options};
+    use crate::config::{Config, LogLevel, merge_config};
+    use crate::messages::handler;
+
+    crate::reset_settings();
+    let config: Config = serde_json::from_str(r#"{"log": {"level": "debug"}, "timeouts": {"request": 5}}"#).unwrap();
+    assert_eq!(config.log.level, LogLevel::Debug);
+    assert_eq!(config.timeouts.request, 5);
+    assert_eq!(config.timeouts.tor_bootstrap, 30);
+    let merged = merge_config(&config, serde_json::json!({ "timeouts": { "torBootstrap": 60 }, "routing": { "torDomains": ["example.com"] } })).unwrap();
+    assert_eq!(merged.timeouts.request, 5);
+    assert_eq!(merged.timeouts.tor_bootstrap, 60);
+    assert_eq!(merged.routing.tor_domains, vec![String::from("example.com")]);
+    assert!(merge_config(&config, serde_json::json!({ "timeouts": { "request": "soon" } })).is_err());
+    assert!(merge_config(&config, serde_json::json!({ "timeouts": { "request": 0 } })).is_err());
+    assert!(merge_config(&config, serde_json::json!({ "timeouts": { "torBootstrap": 0 } })).is_err());
+    assert!(merge_config(&config, serde_json::json!([])).is_err());
+
+    // the command line takes precedence over the config file, see tests/environment.rs for the environment
+    let dir = std::env::temp_dir().join(format!("alby-test-config-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let path = dir.join("config.json").to_string_lossy().to_string();
+    crate::config::save_config(&path, &serde_json::from_value(serde_json::json!({
+        "log": { "file": "/tmp/alby-config-test.log", "level": "debug" },
+        "torDir": "/tmp/alby-config-test-tor",
+        "tor": { "socksPort": 19050 },
+    })).unwrap()).unwrap();
+    let opts = try_get_cli_options(get_args_from_string(&format!("alby --config={} --tor-dir=/tmp/alby-cli-tor --socks-port=auto --log-level=info", &path))).unwrap();
+    crate::apply_cli_options(&opts);
+    assert_eq!(crate::get_logfile_path(), "/tmp/alby-config-test.log");
+    assert_eq!(crate::get_tor_dir_path(), "/tmp/alby-cli-tor");
+    assert_eq!(crate::get_config().tor.socks_port, Some(0));
+    assert!(!crate::is_debug_mode());
+    assert_eq!(crate::get_config().log.level, LogLevel::Info);
+
+    // the extension manages the file, the options of the command line stay in effect
+    let res = handler(serde_json::json!({ "id": "1", "url": "", "method": "GET", "action": "setConfig",
+        "body": r#"{"log": {"level": "debug"}, "routing": {"defaultRoute": "tor"}}"# })).unwrap();
+    assert_eq!(res.status, 200);
+    assert!(crate::is_debug_mode());
+    let body: serde_json::Value = serde_json::from_str(&res.body).unwrap();
+    assert_eq!(body["file"], path.as_str());
+    assert_eq!(body["config"]["routing"]["defaultRoute"], "tor");
+    assert_eq!(body["config"]["tor"]["socksPort"], 19050);
+    assert_eq!(body["effective"]["tor"]["socksPort"], 0);
+    assert_eq!(crate::config::load_config(&path).unwrap().routing.default_route, crate::routing::Route::Tor);
+    let res = handler(serde_json::json!({ "id": "2", "url": "", "method": "GET", "action": "getConfig" })).unwrap();
+    assert_eq!(res.body, body.to_string());
+    let res = handler(serde_json::json!({ "id": "3", "url": "", "method": "GET", "action": "setConfig", "body": r#"{"torDir": 1}"# })).unwrap();
+    assert_eq!(res.status, 500);
+    let res = handler(serde_json::json!({ "id": "4", "url": "", "method": "GET", "action": "setConfig", "body": r#"{"timeouts": {"request": 0}}"# })).unwrap();
+    assert_eq!(res.status, 500);
+    assert_eq!(crate::config::load_config(&path).unwrap().timeouts.request, 75);
+    // a file edited by hand is checked as the changes of the extension
+    fs::write(&path, r#"{"timeouts": {"torBootstrap": 0}}"#).unwrap();
+    assert!(crate::config::load_config(&path).unwrap_err().contains("timeouts must be at least 1 second"));
+    crate::set_debug_mode(false);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[serial]
+pub fn test_configure_tor_keeps_cli_options() {
+    use crate::cli::{get_args_from_string, try_get_cli_options};
+    use crate::config::{load_config, save_config};
+
+    crate::reset_settings();
+    let dir = std::env::temp_dir().join(format!("alby-test-configure-tor-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let path = dir.join("config.json").to_string_lossy().to_string();
+    save_config(&path, &serde_json::from_value(serde_json::json!({ "routing": { "torDomains": ["example.com"] } })).unwrap()).unwrap();
+    let opts = try_get_cli_options(get_args_from_string(&format!("alby --config={} --route=tor --debug --proxy=127.0.0.1:9150 --proxy-password=secret", &path))).unwrap();
+    crate::apply_cli_options(&opts);
+
+    let res = crate::messages::handler(serde_json::json!({ "id": "1", "url": "", "method": "GET", "action": "configureTor",
+        "body": r#"{"useBridges": true, "bridges": ["obfs4 192.0.2.1:443"]}"# })).unwrap();
+    assert_eq!(res.status, 200);
+    let file_config = load_config(&path).unwrap();
+    assert!(file_config.tor.use_bridges);
+    assert_eq!(file_config.tor.proxy, None);
+    assert_eq!(file_config.routing.default_route, crate::routing::Route::Auto);
+    assert_eq!(file_config.routing.tor_domains, vec![String::from("example.com")]);
+    assert_eq!(file_config.log.level, crate::config::LogLevel::Info);
+    assert!(!fs::read_to_string(&path).unwrap().contains("secret"));
+    assert!(crate::get_config().tor.use_bridges);
+    assert_eq!(crate::get_config().routing.default_route, crate::routing::Route::Tor);
+    // the level in effect is reported by getConfig
+    assert_eq!(crate::get_config().log.level, crate::config::LogLevel::Debug);
+    crate::set_debug_mode(false);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[serial]
+pub fn test_runtime_settings() {
+    use crate::messages::handler;
+
+    crate::reset_settings();
+    // the debug mode is shared by all threads, other tests may have left it on
+    crate::set_debug_mode(false);
+
+    let action = |id: &str, action: &str, body: Option<&str>| {
+        let res = handler(serde_json::json!({ "id": id, "url": "", "method": "GET", "action": action, "body": body })).unwrap();
+        let settings = serde_json::from_str::<serde_json::Value>(&res.body).ok();
+        (res.status, settings)
+    };
+    let (status, settings) = action("1", "getSettings", None);
+    assert_eq!(status, 200);
+    let settings = settings.unwrap();
+    assert_eq!(settings["logLevel"], "info");
+    assert_eq!(settings["debugMode"], false);
+    assert_eq!(settings["logFile"], crate::get_logfile_path());
+
+    let (_, settings) = action("2", "setDebugMode", Some(r#"{"enabled": true}"#));
+    assert_eq!(settings.unwrap()["logLevel"], "debug");
+    assert!(crate::is_debug_mode());
+    // like the threads of Tor, the signal handler and the daemon
+    assert!(std::thread::spawn(crate::is_debug_mode).join().unwrap());
+    let (_, settings) = action("3", "setLogLevel", Some(r#"{"level": "info"}"#));
+    assert_eq!(settings.unwrap()["debugMode"], false);
+    assert!(!crate::is_debug_mode());
+    assert_eq!(crate::get_config().log.level, crate::config::LogLevel::Info);
+
+    assert_eq!(action("4", "setLogLevel", Some(r#"{"level": "trace"}"#)).0, 500);
+    assert_eq!(action("5", "setDebugMode", None).0, 500);
+}
+
+#[test]
+pub fn test_external_proxy_cli() {
+    let opts = crate::cli::get_cli_options(crate::cli::get_args_from_string("alby --proxy=127.0.0.1:9150 --proxy-username=u --proxy-password=p=w"));
+    let proxy = opts.proxy.expect("proxy is parsed");
+    assert_eq!(proxy.host, "127.0.0.1");
+    assert_eq!(proxy.port, 9150);
+    assert_eq!(proxy.username, Some(String::from("u")));
+    assert_eq!(proxy.password, Some(String::from("p=w")));
+    assert_eq!(proxy.get_url(), "socks5h://127.0.0.1:9150");
+
+    let opts = crate::cli::get_cli_options(crate::cli::get_args_from_string("alby --proxy-username=u --system-tor"));
+    assert!(opts.proxy.is_none());
+    assert!(opts.use_system_tor);
+}
+
+#[test]
+#[serial]
+pub fn test_migrate_tor_dir() {
+    let root = std::env::temp_dir().join(format!("alby-test-migrate-{}", std::process::id()));
+    let from = root.join("alby-tor");
+    let to = root.join("data").join("tor");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(from.join("keys")).unwrap();
+    fs::write(from.join("cached-microdesc-consensus"), "consensus").unwrap();
+    fs::write(from.join("keys").join("secret_id_key"), "key").unwrap();
+
+    assert!(crate::paths::migrate_tor_dir(&from, &to));
+    assert!(!from.exists());
+    assert_eq!(fs::read_to_string(to.join("cached-microdesc-consensus")).unwrap(), "consensus");
+    assert_eq!(fs::read_to_string(to.join("keys").join("secret_id_key")).unwrap(), "key");
+    // existing data is never overwritten
+    fs::create_dir_all(&from).unwrap();
+    assert!(!crate::paths::migrate_tor_dir(&from, &to));
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+#[cfg(feature = "embedded-tor")]
+pub fn test_onion_auth_keys() {
+    use crate::onion_auth::{parse_onion_address, parse_private_key};
+
+    let address = "wqskhzt3oiz76dgqbqh27j3qw5aeaui3jxyexzuwxqa5czzo24i3z3ad";
+    assert_eq!(parse_onion_address(&format!("https://{}.onion:8080/v1", address)), Ok(address.to_string()));
+    assert_eq!(parse_onion_address(&format!("{}.ONION", address.to_uppercase())), Ok(address.to_string()));
+    assert!(parse_onion_address("facebookcorewwwi.onion").is_err());
+
+    let key = [7u8; 32];
+    let base64_key = base64::encode(key);
+    assert_eq!(parse_private_key(&format!("x25519:{}", base64_key)), Ok(key.to_vec()));
+    assert_eq!(parse_private_key("descriptor:x25519:A4DQOBYHA4DQOBYHA4DQOBYHA4DQOBYHA4DQOBYHA4DQOBYHA4DQ"), Ok(key.to_vec()));
+    assert!(parse_private_key("x25519:AAAA").is_err());
+}
+
+#[test]
+#[cfg(feature = "embedded-tor")]
+pub fn test_bootstrap_status_events() {
+    use crate::tor::{apply_status_event, BootstrapStatus};
+
+    let mut status: BootstrapStatus = Default::default();
+    apply_status_event(&mut status, r#"NOTICE BOOTSTRAP PROGRESS=50 TAG=loading_descriptors SUMMARY="Loading relay descriptors""#);
+    assert_eq!(status.progress, 50);
+    assert_eq!(status.summary, "Loading relay descriptors");
+    assert_eq!(status.warning, None);
+
+    apply_status_event(&mut status, r#"WARN BOOTSTRAP PROGRESS=50 TAG=loading_descriptors SUMMARY="Loading relay descriptors" WARNING="Connection refused \"by peer\"" REASON=CONNECTREFUSED COUNT=1 RECOMMENDATION=ignore"#);
+    assert_eq!(status.warning, Some(String::from(r#"Connection refused "by peer""#)));
+
+    apply_status_event(&mut status, "NOTICE CIRCUIT_ESTABLISHED");
+    assert_eq!(status.progress, 50);
+    apply_status_event(&mut status, r#"NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY="Done""#);
+    assert_eq!(status.progress, 100);
+
+    crate::tor::apply_general_status_event(&mut status, "WARN CLOCK_SKEW SKEW=-3600 SOURCE=CONSENSUS");
+    assert_eq!(status.clock_skew, Some(-3600));
+}
+
+#[test]
+pub fn test_routing() {
+    use crate::routing::{accepts_invalid_certs, get_route, Route, RoutingConfig};
+
+    let config: RoutingConfig = Default::default();
+    let route = |url: &str, requested: Option<Route>| get_route(&reqwest::Url::parse(url).unwrap(), requested, &config);
+    let onion = "https://wqskhzt3oiz76dgqbqh27j3qw5aeaui3jxyexzuwxqa5czzo24i3z3ad.onion:8080";
+    assert_eq!(route(onion, None), Ok(Route::Tor));
+    assert!(route(onion, Some(Route::Direct)).is_err());
+    assert_eq!(route("https://github.com", None), Ok(Route::Direct));
+    assert_eq!(route("https://github.com", Some(Route::Tor)), Ok(Route::Tor));
+    assert_eq!(route("https://192.168.1.10:8080", None), Ok(Route::Direct));
+    assert_eq!(route("https://[fd00::1]:8080", None), Ok(Route::Direct));
+    assert_eq!(route("https://umbrel.local", None), Ok(Route::Direct));
+    assert_eq!(route("https://8.8.8.8", None), Ok(Route::Tor));
+    assert_eq!(route("https://8.8.8.8", Some(Route::Direct)), Ok(Route::Direct));
+
+    let lenient = |url: &str, route: Route| accepts_invalid_certs(&reqwest::Url::parse(url).unwrap(), route, &config);
+    assert!(lenient(onion, Route::Tor));
+    assert!(lenient("https://8.8.8.8", Route::Tor));
+    assert!(!lenient("https://8.8.8.8", Route::Direct));
+    assert!(lenient("https://192.168.1.10:8080", Route::Direct));
+    assert!(lenient("https://umbrel.local", Route::Direct));
+    assert!(!lenient("https://github.com", Route::Direct));
+    assert!(!lenient("https://github.com", Route::Tor));
+
+    let config = RoutingConfig {
+        default_route: Route::Tor,
+        tor_domains: vec![String::from(".example.com")],
+        ..Default::default()
+    };
+    let route = |url: &str| get_route(&reqwest::Url::parse(url).unwrap(), None, &config);
+    assert_eq!(route("https://github.com"), Ok(Route::Tor));
+    assert_eq!(route("https://8.8.8.8"), Ok(Route::Tor));
+    assert_eq!(route("https://10.21.21.9:8080"), Ok(Route::Direct));
+    assert_eq!(route("https://node.example.com"), Ok(Route::Tor));
+    assert_eq!(route("https://example.com.evil.org"), Ok(Route::Tor));
+
+    // only whole labels match, the rest goes the default route
+    let config = RoutingConfig {
+        default_route: Route::Direct,
+        tor_domains: vec![String::from("example.com")],
+        ..Default::default()
+    };
+    let route = |url: &str| get_route(&reqwest::Url::parse(url).unwrap(), None, &config);
+    assert_eq!(route("https://example.com"), Ok(Route::Tor));
+    assert_eq!(route("https://node.Example.com."), Ok(Route::Tor));
+    assert_eq!(route("https://example.com.evil.org"), Ok(Route::Direct));
+    assert_eq!(route("https://evilexample.com"), Ok(Route::Direct));
+}
+
+#[test]
+#[serial]
+pub fn test_diagnose_report() {
+    use crate::diagnose::{diagnose, DiagnoseRequest};
+
+    crate::reset_settings();
+    crate::prepare_log_file();
+    let req = DiagnoseRequest {
+        url: Some(String::from("http://127.0.0.1:1/")),
+        ..Default::default()
+    };
+    let report = diagnose(&req);
+    assert!(report.data_dir.ok);
+    assert!(report.log_file.ok);
+    assert!(report.onion.is_none());
+    let url = report.url.expect("url report");
+    assert!(url.dns.ok);
+    assert!(!url.tcp.ok);
+    assert!(url.http.skipped);
+    let json = serde_json::to_value(&url).unwrap();
+    assert_eq!(json["route"], "direct");
+
+    // the folders are created and Tor is launched by the requests, not by the report
+    let dir = std::env::temp_dir().join(format!("alby-test-diagnose-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let args = format!("alby --config={} --tor-dir={} --log-file={}", dir.join("config.json").to_string_lossy(),
+                       dir.join("data/tor").to_string_lossy(), dir.join("logs/alby.log").to_string_lossy());
+    crate::apply_cli_options(&crate::cli::try_get_cli_options(crate::cli::get_args_from_string(&args)).unwrap());
+    let req = DiagnoseRequest { onion: Some(String::from("wqskhzt3oiz76dgqbqh27j3qw5aeaui3jxyexzuwxqa5czzo24i3z3ad.onion")), ..Default::default() };
+    let report = diagnose(&req);
+    assert!(report.data_dir.ok);
+    assert!(report.log_file.ok);
+    assert!(!dir.join("data").exists());
+    assert!(!dir.join("logs").exists());
+    #[cfg(feature = "embedded-tor")]
+    {
+        assert!(!report.tor.started);
+        assert_eq!(report.onion.unwrap().error, Some(String::from("Tor not started")));
+        assert!(!crate::is_tor_started());
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(feature = "embedded-tor")]
+pub fn test_socks_port() {
+    use crate::tor::{parse_socks_listener_port, SocksListener};
+
+    let opts = crate::cli::get_cli_options(crate::cli::get_args_from_string("alby --socks-port=9055"));
+    assert_eq!(opts.socks_port, Some(9055));
+    let opts = crate::cli::get_cli_options(crate::cli::get_args_from_string("alby --socks-port=auto"));
+    assert_eq!(opts.socks_port, Some(0));
+
+    assert_eq!(parse_socks_listener_port(r#"net/listeners/socks="127.0.0.1:19050""#), Some(19050));
+    assert_eq!(parse_socks_listener_port(r#"net/listeners/socks="unix:/run/alby/socks" "[::1]:9050""#), Some(9050));
+    assert_eq!(parse_socks_listener_port("net/listeners/socks="), None);
+
+    let lines = crate::tor::get_torrc_lines(&Default::default(), "/home/u/.local/share/alby/tor", &SocksListener::Unix(String::from("/home/u/.local/share/alby/tor/run/socks")));
+    assert!(lines.contains(&String::from(r#"SocksPort unix:"/home/u/.local/share/alby/tor/run/socks""#)));
+}
+
+#[test]
+#[serial]
+#[cfg(all(unix, feature = "embedded-tor"))]
+pub fn test_socks_relay() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use crate::test_servers::{EchoServer, SocksStandIn};
+
+    crate::reset_settings();
+    crate::prepare_log_file();
+    let server = EchoServer::start();
+    let path = std::env::temp_dir().join(format!("alby-test-socks-{}", std::process::id()));
+    // the stand-in takes the place of the unix socket of the embedded Tor
+    let socks = SocksStandIn::start_unix(&path, server.addr);
+    let socket = path.to_string_lossy().to_string();
+    let port = crate::socks_relay::get_port(&socket).unwrap();
+    assert_eq!(crate::socks_relay::get_port(&socket).unwrap(), port);
+    let mut config = crate::get_config();
+    config.tor.proxy = None;
+    crate::set_config(config);
+    crate::set_tor_is_started(true);
+    crate::set_tor_is_ready(true);
+    crate::set_tor_port(port);
+
+    let onion = "wqskhzt3oiz76dgqbqh27j3qw5aeaui3jxyexzuwxqa5czzo24i3z3ad.onion";
+    let msg = ReqMessage { id: "29".to_string(), url: format!("https://{}:8080/v1/getinfo", onion), ..Default::default() };
+    let res = crate::requests::get_response(msg).unwrap();
+    assert_eq!(res.status, 200);
+    assert_eq!(res.headers["x-test-server"], "echo");
+    assert_eq!(socks.get_targets(), vec![format!("{}:8080", onion)]);
+    assert_eq!(socks.get_credentials(), vec![(crate::get_tor_username(), crate::get_tor_password())]);
+
+    // other local processes don't get through without the credentials
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(&[5, 1, 0]).unwrap();
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [5, 0xff]);
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(&[5, 1, 2, 1, 1, b'u', 1, b'p']).unwrap();
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [5, 2]);
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [1, 1]);
+    assert_eq!(socks.get_targets().len(), 1);
+
+    crate::set_tor_port(0);
+    crate::set_tor_is_ready(false);
+    crate::set_tor_is_started(false);
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+#[cfg(feature = "embedded-tor")]
+pub fn test_onion_service_requests() {
+    use crate::onion_service::{add_onion_service, AddOnionServiceRequest, get_onion_services_dir, get_target, load_onion_services, OnionService, remove_onion_service, save_onion_service};
+
+    let req: AddOnionServiceRequest = serde_json::from_str(r#"{"port": 8080}"#).unwrap();
+    assert_eq!((req.port, req.host, req.virtual_port, req.persistent), (8080, None, None, false));
+    let req: AddOnionServiceRequest = serde_json::from_str(r#"{"port": 10009, "host": "umbrel.local", "virtualPort": 443, "persistent": true}"#).unwrap();
+    assert_eq!((req.host.as_deref(), req.virtual_port, req.persistent), (Some("umbrel.local"), Some(443), true));
+    assert!(serde_json::from_str::<AddOnionServiceRequest>(r#"{"port": 70000}"#).is_err());
+
+    assert_eq!(get_target("127.0.0.1", 8080), Ok(String::from("127.0.0.1:8080")));
+    assert_eq!(get_target("[fd00::1]", 8080), Ok(String::from("[fd00::1]:8080")));
+    assert_eq!(get_target("::1", 8080), Ok(String::from("[::1]:8080")));
+    assert_eq!(get_target("umbrel.local", 10009), Ok(String::from("umbrel.local:10009")));
+    // the host is formatted into the ADD_ONION command
+    for host in ["", "127.0.0.1 Flags=Detach", "127.0.0.1\r\nSIGNAL SHUTDOWN", "node:80", "-node", "a..b", "[::1"] {
+        assert!(get_target(host, 80).is_err(), "{}", host);
+    }
+
+    let tor_dir = std::env::temp_dir().join(format!("alby-test-onion-services-{}", std::process::id())).to_string_lossy().to_string();
+    let _ = fs::remove_dir_all(&tor_dir);
+    // refused before connecting to the control port
+    let req = AddOnionServiceRequest { port: 8080, host: Some(String::from("127.0.0.1:80\r\nDEL_ONION x")), virtual_port: None, persistent: false };
+    assert!(add_onion_service(&tor_dir, &req).unwrap_err().starts_with("Invalid host"));
+    let req = AddOnionServiceRequest { port: 8080, host: None, virtual_port: Some(0), persistent: false };
+    assert!(add_onion_service(&tor_dir, &req).is_err());
+
+    let service_id = "wqskhzt3oiz76dgqbqh27j3qw5aeaui3jxyexzuwxqa5czzo24i3z3ad";
+    let service = OnionService {
+        service_id: service_id.to_string(),
+        virtual_port: 443,
+        target: String::from("127.0.0.1:8080"),
+        private_key: Some(format!("ED25519-V3:{}", base64::encode([7u8; 64]))),
+    };
+    save_onion_service(&tor_dir, &service).unwrap();
+    let path = get_onion_services_dir(&tor_dir).join(format!("{}.json", service_id));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::metadata(get_onion_services_dir(&tor_dir)).unwrap().permissions().mode() & 0o777, 0o700);
+    }
+    assert_eq!(load_onion_services(&tor_dir), vec![Ok(service.clone())]);
+    // an edited file is not replayed to the control port
+    let tampered = OnionService { target: String::from("127.0.0.1:8080\r\nSIGNAL SHUTDOWN"), ..service.clone() };
+    fs::write(&path, serde_json::to_string(&tampered).unwrap()).unwrap();
+    assert!(load_onion_services(&tor_dir)[0].is_err());
+    let tampered = OnionService { private_key: Some(String::from("ED25519-V3:key Flags=")), ..service };
+    fs::write(&path, serde_json::to_string(&tampered).unwrap()).unwrap();
+    assert!(load_onion_services(&tor_dir)[0].is_err());
+
+    assert!(remove_onion_service(&tor_dir, "../../config").is_err());
+    assert!(remove_onion_service(&tor_dir, "abc").is_err());
+    assert_eq!(remove_onion_service(&tor_dir, &format!("{}.onion", service_id)), Ok(true));
+    assert!(!path.exists());
+    // no file and no running Tor
+    assert_eq!(remove_onion_service(&tor_dir, service_id), Ok(false));
+    let _ = fs::remove_dir_all(&tor_dir);
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(all(unix, feature = "embedded-tor"))]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(all(unix, feature = "embedded-tor"))]
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use native_tls::{Identity, TlsAcceptor};
+use serde_json::json;
+
+/// Self-signed for `localhost` and `127.0.0.1`, valid until 2126
+pub const CERT_PEM: &str = include_str!("../tests/fixtures/localhost.crt");
+const KEY_PEM: &str = include_str!("../tests/fixtures/localhost.key");
+
+/// HTTPS server on localhost which answers every request with JSON of the request:
+/// `method`, `path`, `headers` (lowercase names) and `body`, and the header `X-Test-Server`.
+pub struct EchoServer {
+    pub addr: SocketAddr,
+}
+
+impl EchoServer {
+    pub fn start() -> EchoServer {
+        let identity = Identity::from_pkcs8(CERT_PEM.as_bytes(), KEY_PEM.as_bytes()).unwrap();
+        let acceptor = Arc::new(TlsAcceptor::new(identity).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let acceptor = acceptor.clone();
+                thread::spawn(move || {
+                    // fails when the client rejects the certificate
+                    if let Ok(stream) = acceptor.accept(stream) {
+                        let _ = echo(stream);
+                    }
+                });
+            }
+        });
+        EchoServer { addr }
+    }
+
+    pub fn get_port(&self) -> u16 {
+        self.addr.port()
+    }
+}
+
+/// One request per connection
+fn echo<S: Read + Write>(stream: S) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    let length = headers.get("content-length").and_then(|l| l.parse::<usize>().ok()).unwrap_or(0);
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body)?;
+    let status = match path.as_str() {
+        "/missing" => "404 Not Found",
+        _ => "200 OK",
+    };
+    let content = json!({ "method": method, "path": path, "headers": headers, "body": String::from_utf8_lossy(&body) }).to_string();
+    let mut stream = reader.into_inner();
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/json\r\nX-Test-Server: echo\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, content.len(), content)?;
+    stream.flush()
+}
+
+/// SOCKS5 proxy on localhost in place of Tor: every connection goes to the upstream,
+/// whatever the requested host is, so `.onion` addresses reach a local server.
+pub struct SocksStandIn {
+    pub addr: SocketAddr,
+    connections: Arc<Mutex<Connections>>,
+}
+
+#[derive(Default)]
+struct Connections {
+    /// `host:port` of the CONNECT requests
+    targets: Vec<String>,
+    /// Username and password of the clients which authenticated
+    credentials: Vec<(String, String)>,
+}
+
+impl SocksStandIn {
+    pub fn start(upstream: SocketAddr) -> SocksStandIn {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = std::iter::from_fn(move || Some(listener.accept().map(|(s, _)| s)));
+        SocksStandIn { addr, connections: accept(incoming, upstream) }
+    }
+
+    /// Listens on a unix socket like the embedded Tor on Unix, `addr` is not used then.
+    #[cfg(all(unix, feature = "embedded-tor"))]
+    pub fn start_unix(path: &Path, upstream: SocketAddr) -> SocksStandIn {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).unwrap();
+        let incoming = std::iter::from_fn(move || Some(listener.accept().map(|(s, _)| s)));
+        let connections = accept(incoming, upstream);
+        SocksStandIn { addr: SocketAddr::from(([127, 0, 0, 1], 0)), connections }
+    }
+
+    pub fn get_targets(&self) -> Vec<String> {
+        self.connections.lock().unwrap().targets.clone()
+    }
+
+    #[cfg(feature = "embedded-tor")]
+    pub fn get_credentials(&self) -> Vec<(String, String)> {
+        self.connections.lock().unwrap().credentials.clone()
+    }
+}
+
+fn accept<S: Stream, I: Iterator<Item = io::Result<S>> + Send + 'static>(incoming: I, upstream: SocketAddr) -> Arc<Mutex<Connections>> {
+    let connections = Arc::new(Mutex::new(Connections::default()));
+    let shared = connections.clone();
+    thread::spawn(move || {
+        for stream in incoming.flatten() {
+            let connections = shared.clone();
+            thread::spawn(move || {
+                let _ = relay(stream, upstream, &connections);
+            });
+        }
+    });
+    connections
+}
+
+/// Client connection of the stand-in, copied in both directions at the same time
+trait Stream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown_write(&self);
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown_write(&self) {
+        let _ = self.shutdown(Shutdown::Write);
+    }
+}
+
+#[cfg(all(unix, feature = "embedded-tor"))]
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown_write(&self) {
+        let _ = self.shutdown(Shutdown::Write);
+    }
+}
+
+/// RFC 1928, only CONNECT. With username and password (RFC 1929) when the client offers them,
+/// as Tor does to isolate the streams, any credentials are accepted. Without authentication otherwise.
+fn relay<S: Stream>(mut client: S, upstream: SocketAddr, connections: &Mutex<Connections>) -> io::Result<()> {
+    let mut greeting = [0u8; 2];
+    client.read_exact(&mut greeting)?;
+    let mut methods = vec![0u8; greeting[1] as usize];
+    client.read_exact(&mut methods)?;
+    if methods.contains(&0x02) {
+        client.write_all(&[0x05, 0x02])?;
+        let mut version = [0u8; 2];
+        client.read_exact(&mut version)?;
+        let mut username = vec![0u8; version[1] as usize];
+        client.read_exact(&mut username)?;
+        let mut length = [0u8; 1];
+        client.read_exact(&mut length)?;
+        let mut password = vec![0u8; length[0] as usize];
+        client.read_exact(&mut password)?;
+        connections.lock().unwrap().credentials.push((String::from_utf8_lossy(&username).to_string(), String::from_utf8_lossy(&password).to_string()));
+        client.write_all(&[0x01, 0x00])?;
+    } else if methods.contains(&0x00) {
+        client.write_all(&[0x05, 0x00])?;
+    } else {
+        client.write_all(&[0x05, 0xff])?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "no supported authentication method"));
+    }
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request)?;
+    let host = match request[3] {
+        0x01 => {
+            let mut ip = [0u8; 4];
+            client.read_exact(&mut ip)?;
+            ip.map(|b| b.to_string()).join(".")
+        },
+        0x03 => {
+            let mut length = [0u8; 1];
+            client.read_exact(&mut length)?;
+            let mut name = vec![0u8; length[0] as usize];
+            client.read_exact(&mut name)?;
+            String::from_utf8_lossy(&name).to_string()
+        },
+        _ => {
+            client.write_all(&[0x05, 0x08, 0x00, 0x01, 0, 0, 0, 0, 0, 0])?;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "address type not supported"));
+        },
+    };
+    let mut port = [0u8; 2];
+    client.read_exact(&mut port)?;
+    connections.lock().unwrap().targets.push(format!("{}:{}", host, u16::from_be_bytes(port)));
+    let server = TcpStream::connect(upstream)?;
+    client.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])?;
+    let (mut client_read, mut server_write) = (client.try_clone()?, server.try_clone()?);
+    let upload = thread::spawn(move || {
+        let _ = io::copy(&mut client_read, &mut server_write);
+        let _ = server_write.shutdown(Shutdown::Write);
+    });
+    let (mut server_read, mut client_write) = (server, client);
+    let _ = io::copy(&mut server_read, &mut client_write);
+    client_write.shutdown_write();
+    let _ = upload.join();
+    Ok(())
+}
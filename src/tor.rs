@@ -1,39 +1,88 @@
 use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use libtor::{LogDestination, LogLevel, Tor, TorFlag};
+use serde::Serialize;
 
-use crate::{exit, get_log, write_debug, write_debug_to};
+use crate::{exit, write_debug, write_debug_to};
+use crate::config::TorConfig;
+use crate::control::{CONTROL_AUTH_COOKIE_FILE, CONTROL_PORT_FILE, parse_keywords, TorControl};
+use crate::onion_service::restore_onion_services;
+use crate::onion_auth::{create_private_dir, get_onion_auth_dir, ONION_AUTH_DIR};
 use crate::messages::{ResMessage, send_stdout_msg};
 
+/// Random ports tried before letting Tor choose one
+const SOCKS_PORT_ATTEMPTS: u8 = 20;
+/// Folder inside the Tor data directory with the SOCKS socket, only accessible by the user
+const SOCKS_SOCKET_DIR: &str = "run";
+const SOCKS_SOCKET_FILE: &str = "socks";
+/// Size of `sun_path` is 108 bytes on Linux and 104 on macOS
+const SOCKS_SOCKET_MAX_PATH: usize = 104;
+/// How long Tor may take to open its control port after launch
+const CONTROL_PORT_TIMEOUT: Duration = Duration::from_secs(120);
+
 pub fn launch_tor() {
-    if crate::is_tor_started() {
+    // otherwise it will be possible to launch 2 starting processes
+    if !crate::try_set_tor_is_started() {
         return;
     }
-    crate::set_tor_is_started(true); // otherwise it will be possible to launch 2 starting processes
-    let port = crate::get_tor_port();
+    let tor_config = crate::get_config().tor;
+    let tor_dir = crate::get_tor_dir_path();
+    let listener = match select_socks_listener(&tor_config, &tor_dir) {
+        Ok(l) => l,
+        Err(err) => {
+            fail_launch(format!("Can not select SOCKS port: {}", err));
+            return;
+        }
+    };
     let username = crate::get_tor_username();
     let password = crate::get_tor_password();
     let log_file = crate::get_logfile_path();
-    let tor_dir = crate::get_tor_dir_path();
-    let lock_file = crate::get_lock_file_path();
-    let debug_mode = crate::is_debug_mode();
-    write_debug(format!("Starting Tor on port {}, user: {}, in folder {}. Log redirected to {}", port, username, &tor_dir, &log_file));
+    write_debug(format!("Starting Tor on {}, user: {}, in folder {}. Log redirected to {}", &listener, username, &tor_dir, &log_file));
+    if tor_config.use_bridges {
+        write_debug(format!("Using {} bridge(s)", tor_config.bridges.len()));
+    }
+    let torrc = match write_torrc(&tor_dir, &tor_config, &listener) {
+        Ok(path) => path,
+        Err(err) => {
+            fail_launch(format!("Can not write torrc: {}", err));
+            return;
+        }
+    };
 
+    let watcher_tor_dir = tor_dir.clone();
+    let watcher_log_file = log_file.clone();
+    // the port chosen by Tor is read back from its control port
+    let read_socks_port = listener == SocksListener::Port(0);
+    thread::spawn(move || watch_bootstrap(watcher_tor_dir, watcher_log_file, read_socks_port));
+
+    TOR_LAUNCHED.store(true, Ordering::SeqCst);
     thread::spawn(move || {
         let tor_thread = Tor::new()
+            .flag(TorFlag::ConfigFile(torrc))
             .flag(TorFlag::DataDirectory(tor_dir))
-            .flag(TorFlag::ControlPort(0))
             .flag(TorFlag::LogTo(LogLevel::Notice, LogDestination::File(log_file.clone())))
             .flag(TorFlag::Quiet())
             .flag(TorFlag::Socks5ProxyUsername(username))
             .flag(TorFlag::Socks5ProxyPassword(password))
-            .flag(TorFlag::SocksPort(port))
             .start_background();
-        match tor_thread.join() {
+        let result = tor_thread.join();
+        TOR_STOPPED.store(true, Ordering::SeqCst);
+        if crate::shutdown::is_shutting_down() {
+            write_debug_to("Tor has stopped", &log_file, crate::is_debug_mode());
+            return;
+        }
+        match result {
             Ok(r) => match r {
                 Ok(result) => {
-                    write_debug_to(format!("Tor thread was terminated: {}", result), &log_file, debug_mode);
+                    write_debug_to(format!("Tor thread was terminated: {}", result), &log_file, crate::is_debug_mode());
                     send_stdout_msg(ResMessage {
                         id: "status".to_string(),
                         status: result as u16,
@@ -43,10 +92,10 @@ pub fn launch_tor() {
                             (String::from("X-Alby-description"), String::from("Tor thread was terminated"))
                         ])
                     });
-                    exit(result as i32, lock_file);
+                    exit(result as i32);
                 },
                 Err(err) => {
-                    write_debug_to(format!("Can not spawn Tor thread: {:#?}", err), &log_file, debug_mode);
+                    write_debug_to(format!("Can not spawn Tor thread: {:#?}", err), &log_file, crate::is_debug_mode());
                     send_stdout_msg(ResMessage {
                         id: "status".to_string(),
                         status: 502,
@@ -56,7 +105,7 @@ pub fn launch_tor() {
                             (String::from("X-Alby-description"), String::from("Can not spawn Tor thread"))
                         ])
                     });
-                    exit(1, lock_file);
+                    exit(1);
                 }
             },
             Err(_) => {
@@ -66,15 +115,327 @@ pub fn launch_tor() {
     });
 }
 
-pub fn wait_for_tor(seconds: u8, log_file: &str) -> bool {
-    let pid = crate::get_pid_key();
-    for _ in 0..seconds {
-        let log = get_log(log_file);
-        if log.contains("Bootstrapped 100% (done):") && log.contains(&pid) {
+/// Set when the Tor thread is spawned and when it ends, read by the shutdown on another thread
+static TOR_LAUNCHED: AtomicBool = AtomicBool::new(false);
+static TOR_STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Asks the launched Tor to exit through its control port and waits for it until the deadline.
+pub fn stop_tor(tor_dir: &str, deadline: Instant) -> Result<(), String> {
+    if !TOR_LAUNCHED.load(Ordering::SeqCst) || TOR_STOPPED.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    let mut control = TorControl::connect(tor_dir)?;
+    // a client exits right away, without ShutdownWaitLength of relays
+    control.command("SIGNAL SHUTDOWN")?;
+    while !TOR_STOPPED.load(Ordering::SeqCst) {
+        if Instant::now() > deadline {
+            return Err(String::from("Tor did not stop in time"));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+fn fail_launch(err: String) {
+    write_debug(&err);
+    crate::set_tor_is_started(false);
+    // waiting requests get the error instead of the timeout
+    update_bootstrap_status(|s| *s = BootstrapStatus { error: Some(err), ..Default::default() });
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SocksListener {
+    /// TCP port on 127.0.0.1, 0 lets Tor choose it
+    Port(u16),
+    /// Path of the unix socket
+    Unix(String),
+}
+
+impl Display for SocksListener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SocksListener::Port(0) => write!(f, "port auto"),
+            SocksListener::Port(port) => write!(f, "port {}", port),
+            SocksListener::Unix(path) => write!(f, "unix socket {}", path),
+        }
+    }
+}
+
+/// A unix socket (in a folder only the user can access) unless disabled in the config or not supported, a port otherwise.
+/// The requests reach the unix socket through a local relay.
+fn select_socks_listener(config: &TorConfig, tor_dir: &str) -> Result<SocksListener, String> {
+    #[cfg(unix)]
+    crate::set_tor_socks_socket(None);
+    if cfg!(unix) && config.socks_port.is_none() && config.socks_unix_socket.unwrap_or(true) {
+        let dir = Path::new(tor_dir).join(SOCKS_SOCKET_DIR);
+        let path = dir.join(SOCKS_SOCKET_FILE).to_string_lossy().to_string();
+        // longer paths do not fit into sockaddr_un
+        if path.len() < SOCKS_SOCKET_MAX_PATH {
+            create_private_dir(&dir)?;
+            // left by the previous launch, Tor can not bind the path otherwise
+            let _ = fs::remove_file(&path);
+            #[cfg(unix)]
+            {
+                crate::set_tor_port(crate::socks_relay::get_port(&path)?);
+                crate::set_tor_socks_socket(Some(path.clone()));
+            }
+            return Ok(SocksListener::Unix(path));
+        }
+        write_debug(format!("Path {} is too long for a unix socket, using a TCP port", &path));
+    }
+    let port = select_socks_port(config.socks_port)?;
+    crate::set_tor_port(port);
+    Ok(SocksListener::Port(port))
+}
+
+/// The configured port (0 for `auto`), otherwise a random one which is not in use.
+fn select_socks_port(configured: Option<u16>) -> Result<u16, String> {
+    match configured {
+        Some(0) => Ok(0),
+        Some(port) => match is_port_free(port) {
+            true => Ok(port),
+            false => Err(format!("port {} is already in use", port)),
+        },
+        None => {
+            for _ in 0..SOCKS_PORT_ATTEMPTS {
+                let port = crate::get_random_port();
+                if is_port_free(port) {
+                    return Ok(port);
+                }
+            }
+            // let Tor choose, the port is read back from the control port
+            Ok(0)
+        }
+    }
+}
+
+fn is_port_free(port: u16) -> bool {
+    TcpListener::bind(("127.0.0.1", port)).is_ok()
+}
+
+/// Lines of the torrc file for the options which are not passed as command-line flags.
+pub fn get_torrc_lines(config: &TorConfig, tor_dir: &str, listener: &SocksListener) -> Vec<String> {
+    let tor_dir = Path::new(tor_dir);
+    let mut lines = vec![
+        match listener {
+            SocksListener::Port(0) => String::from("SocksPort auto"),
+            SocksListener::Port(port) => format!("SocksPort 127.0.0.1:{}", port),
+            SocksListener::Unix(path) => format!("SocksPort unix:\"{}\"", path),
+        },
+        String::from("ControlPort auto"),
+        format!("ControlPortWriteToFile {}", tor_dir.join(CONTROL_PORT_FILE).to_string_lossy()),
+        String::from("CookieAuthentication 1"),
+        format!("CookieAuthFile {}", tor_dir.join(CONTROL_AUTH_COOKIE_FILE).to_string_lossy()),
+        format!("ClientOnionAuthDir {}", tor_dir.join(ONION_AUTH_DIR).to_string_lossy()),
+    ];
+    if !config.use_bridges {
+        return lines;
+    }
+    lines.push(String::from("UseBridges 1"));
+    for bridge in &config.bridges {
+        lines.push(format!("Bridge {}", bridge.trim()));
+    }
+    let mut transports: Vec<(&String, &String)> = config.transport_plugins.iter().collect();
+    transports.sort();
+    for (transport, path) in transports {
+        lines.push(format!("ClientTransportPlugin {} exec {}", transport, path));
+    }
+    lines
+}
+
+fn write_torrc(tor_dir: &str, config: &TorConfig, listener: &SocksListener) -> Result<String, String> {
+    if config.use_bridges {
+        if config.bridges.is_empty() {
+            write_debug("⚠️ UseBridges is set, but no bridges are configured");
+        }
+        for transport in config.get_bridge_transports() {
+            if !config.transport_plugins.contains_key(&transport) {
+                write_debug(format!("⚠️ No transport plugin configured for bridges of type '{}'", transport));
+            }
+        }
+    }
+    fs::create_dir_all(tor_dir).map_err(|e| format!("Can not create Tor folder {}: {}", tor_dir, e))?;
+    create_private_dir(&get_onion_auth_dir(tor_dir))?;
+    // stale file from the previous launch, Tor writes the new port after start
+    let _ = fs::remove_file(Path::new(tor_dir).join(CONTROL_PORT_FILE));
+    let path = Path::new(tor_dir).join("torrc");
+    let mut content = get_torrc_lines(config, tor_dir, listener).join("\n");
+    content.push('\n');
+    fs::write(&path, content).map_err(|e| format!("Can not write {}: {}", path.to_string_lossy(), e))?;
+    Ok(path.to_string_lossy().to_string())
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BootstrapStatus {
+    pub progress: u8,
+    pub summary: String,
+    /// Last warning reported by Tor
+    pub warning: Option<String>,
+    /// Set when Tor can not finish bootstrapping anymore
+    pub error: Option<String>,
+    /// Seconds the local clock is off according to Tor (`CLOCK_SKEW` event)
+    pub clock_skew: Option<i64>,
+}
+
+static BOOTSTRAP: Mutex<BootstrapStatus> = Mutex::new(BootstrapStatus {
+    progress: 0,
+    summary: String::new(),
+    warning: None,
+    error: None,
+    clock_skew: None,
+});
+static BOOTSTRAP_CHANGED: Condvar = Condvar::new();
+
+pub fn get_bootstrap_status() -> BootstrapStatus {
+    BOOTSTRAP.lock().map(|s| s.clone()).unwrap_or_default()
+}
+
+fn update_bootstrap_status<F: FnOnce(&mut BootstrapStatus)>(update: F) {
+    if let Ok(mut status) = BOOTSTRAP.lock() {
+        update(&mut status);
+    }
+    BOOTSTRAP_CHANGED.notify_all();
+}
+
+/// Follows the bootstrap of the launched Tor through `STATUS_CLIENT` events of its control port.
+fn watch_bootstrap(tor_dir: String, log_file: String, read_socks_port: bool) {
+    update_bootstrap_status(|s| *s = Default::default());
+    let port_file = Path::new(&tor_dir).join(CONTROL_PORT_FILE);
+    let deadline = Instant::now() + CONTROL_PORT_TIMEOUT;
+    // Tor writes the file when its control port is open
+    while !port_file.exists() {
+        if Instant::now() > deadline {
+            update_bootstrap_status(|s| s.error = Some(String::from("Tor control port was not opened")));
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let mut control = match TorControl::connect(&tor_dir)
+        .and_then(|mut c| c.command("SETEVENTS STATUS_CLIENT STATUS_GENERAL WARN").map(|_| c)) {
+        Ok(c) => c,
+        Err(err) => {
+            write_debug_to(format!("Can not follow Tor bootstrap: {}", &err), &log_file, crate::is_debug_mode());
+            update_bootstrap_status(|s| s.error = Some(err));
+            return;
+        }
+    };
+    for result in restore_onion_services(&mut control, &tor_dir) {
+        match result {
+            Ok(onion) => write_debug_to(format!("Onion service {} is published", onion), &log_file, crate::is_debug_mode()),
+            Err(err) => write_debug_to(err, &log_file, crate::is_debug_mode()),
+        };
+    }
+    if read_socks_port {
+        match control.command("GETINFO net/listeners/socks") {
+            // 250-net/listeners/socks="127.0.0.1:19050"
+            Ok(reply) => match reply.lines.iter().find_map(|l| parse_socks_listener_port(l)) {
+                Some(port) => crate::set_tor_port(port),
+                None => {
+                    write_debug_to("SOCKS port of Tor is not found", &log_file, crate::is_debug_mode());
+                }
+            },
+            Err(err) => {
+                write_debug_to(format!("Can not get SOCKS port of Tor: {}", err), &log_file, crate::is_debug_mode());
+            }
+        }
+    }
+    // the phase before subscription, events of the later phases are queued
+    if let Ok(reply) = control.command("GETINFO status/bootstrap-phase") {
+        for line in reply.lines {
+            if let Some(phase) = line.strip_prefix("status/bootstrap-phase=") {
+                update_bootstrap_status(|s| apply_status_event(s, phase));
+            }
+        }
+    }
+    loop {
+        match control.read_event() {
+            Ok(event) => {
+                for line in event.lines {
+                    if let Some(status) = line.strip_prefix("STATUS_CLIENT ") {
+                        update_bootstrap_status(|s| apply_status_event(s, status));
+                    } else if let Some(status) = line.strip_prefix("STATUS_GENERAL ") {
+                        update_bootstrap_status(|s| apply_general_status_event(s, status));
+                    } else if let Some(warning) = line.strip_prefix("WARN ") {
+                        let warning = warning.to_string();
+                        update_bootstrap_status(|s| s.warning = Some(warning));
+                    }
+                }
+            },
+            Err(err) => {
+                write_debug_to(format!("Tor control connection is closed: {}", &err), &log_file, crate::is_debug_mode());
+                update_bootstrap_status(|s| if s.progress < 100 {
+                    s.error = Some(String::from("Tor has stopped"));
+                });
+                return;
+            }
+        }
+    }
+}
+
+/// `net/listeners/socks="127.0.0.1:19050" "[::1]:19050"` => 19050
+pub fn parse_socks_listener_port(line: &str) -> Option<u16> {
+    let listeners = line.strip_prefix("net/listeners/socks=")?;
+    listeners.split_whitespace()
+        .map(|l| l.trim_matches('"'))
+        .filter(|l| !l.starts_with("unix:"))
+        .find_map(|l| l.rsplit_once(':').and_then(|(_, port)| port.parse::<u16>().ok()))
+}
+
+/// Applies `NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY="Done"` or
+/// `WARN BOOTSTRAP PROGRESS=10 ... WARNING="Connection refused" RECOMMENDATION=warn`
+pub fn apply_status_event(status: &mut BootstrapStatus, event: &str) {
+    let mut words = event.split_whitespace();
+    let severity = words.next().unwrap_or_default();
+    if words.next() != Some("BOOTSTRAP") {
+        return;
+    }
+    let keywords = parse_keywords(event);
+    if let Some(progress) = keywords.get("PROGRESS").and_then(|p| p.parse::<u8>().ok()) {
+        status.progress = progress;
+    }
+    if let Some(summary) = keywords.get("SUMMARY") {
+        status.summary = summary.to_string();
+    }
+    if severity == "WARN" || severity == "ERR" {
+        status.warning = keywords.get("WARNING").cloned();
+    }
+}
+
+/// Applies `WARN CLOCK_SKEW SKEW=-3600 SOURCE=CONSENSUS`
+pub fn apply_general_status_event(status: &mut BootstrapStatus, event: &str) {
+    if event.split_whitespace().nth(1) != Some("CLOCK_SKEW") {
+        return;
+    }
+    if let Some(skew) = parse_keywords(event).get("SKEW").and_then(|s| s.parse::<i64>().ok()) {
+        status.clock_skew = Some(skew);
+    }
+}
+
+/// Blocks until Tor reports 100% bootstrap, it fails or the timeout is reached.
+pub fn wait_for_tor(timeout: Duration) -> Result<(), String> {
+    let deadline = Instant::now() + timeout;
+    let mut status = BOOTSTRAP.lock().map_err(|_| String::from("Tor status is not available"))?;
+    loop {
+        if status.progress >= 100 {
             crate::set_tor_is_ready(true);
-            return true;
+            return Ok(());
+        }
+        if let Some(err) = &status.error {
+            return Err(err.clone());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            let mut err = format!("Tor bootstrap timed out after {}s at {}%", timeout.as_secs(), status.progress);
+            if !status.summary.is_empty() {
+                err = format!("{} ({})", err, status.summary);
+            }
+            if let Some(warning) = &status.warning {
+                err = format!("{}, last warning: {}", err, warning);
+            }
+            return Err(err);
         }
-        thread::sleep(std::time::Duration::from_secs(1));
+        status = BOOTSTRAP_CHANGED.wait_timeout(status, deadline - now)
+            .map_err(|_| String::from("Tor status is not available"))?.0;
     }
-    false
 }
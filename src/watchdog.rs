@@ -0,0 +1,75 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use sysinfo::{Pid, PidExt, ProcessExt, ProcessRefreshKind, System, SystemExt};
+
+use crate::shutdown::{shutdown, ShutdownContext};
+use crate::write_debug_to;
+
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The shim watches the browser too and may fall back to serving it itself
+static WATCHING: AtomicBool = AtomicBool::new(false);
+
+/// Shuts the companion down when the browser is gone but the end of input was not received,
+/// e.g. when the browser has crashed and a child process still holds the pipe.
+pub fn watch_parent() {
+    if WATCHING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let parent = match get_parent_pid() {
+        Some(pid) => pid,
+        None => {
+            crate::write_debug("Parent process is unknown, watchdog is not started");
+            return;
+        }
+    };
+    let ctx = ShutdownContext::current();
+    thread::spawn(move || {
+        let mut sys = System::new();
+        loop {
+            thread::sleep(WATCHDOG_INTERVAL);
+            let reason = if !is_process_alive(&mut sys, parent) || get_parent_pid() != Some(parent) {
+                format!("Parent process {} has exited", parent)
+            } else if is_stdin_closed() {
+                String::from("Input from the browser is closed")
+            } else {
+                continue;
+            };
+            write_debug_to(reason, &ctx.log_file, crate::is_debug_mode());
+            shutdown(0, &ctx);
+        }
+    });
+}
+
+/// The browser, unless the process was reparented (to init or a subreaper) after the browser exit.
+pub fn get_parent_pid() -> Option<u32> {
+    let pid = Pid::from_u32(std::process::id());
+    let mut sys = System::new();
+    if !sys.refresh_process_specifics(pid, ProcessRefreshKind::new()) {
+        return None;
+    }
+    sys.process(pid).and_then(|p| p.parent()).map(|p| p.as_u32())
+}
+
+/// Refreshes only the given process, unlike `System::new_all()`.
+pub fn is_process_alive(sys: &mut System, pid: u32) -> bool {
+    sys.refresh_process_specifics(Pid::from_u32(pid), ProcessRefreshKind::new())
+}
+
+/// Hang-up without pending data: messages which are left are read by the event loop first.
+#[cfg(unix)]
+fn is_stdin_closed() -> bool {
+    let mut fds = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+    // SAFETY: one valid pollfd, zero timeout
+    let ready = unsafe { libc::poll(&mut fds, 1, 0) };
+    ready > 0
+        && fds.revents & (libc::POLLHUP | libc::POLLERR | libc::POLLNVAL) != 0
+        && fds.revents & libc::POLLIN == 0
+}
+
+#[cfg(not(unix))]
+fn is_stdin_closed() -> bool {
+    false
+}
@@ -0,0 +1,40 @@
+use std::fs;
+use std::process::Command;
+
+const HOST: &str = env!("CARGO_BIN_EXE_alby");
+
+/// The environment takes precedence over the config file, the command line over the environment
+#[test]
+fn test_environment_variables() {
+    let dir = std::env::temp_dir().join(format!("alby-test-env-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.json");
+    fs::write(&config, serde_json::json!({
+        "log": { "file": dir.join("config.log") },
+        "torDir": dir.join("config-tor"),
+    }).to_string()).unwrap();
+    let output = Command::new(HOST)
+        .args(["doctor", "--json"])
+        .arg(format!("--tor-dir={}", dir.join("cli-tor").to_string_lossy()))
+        .env("ALBY_CONFIG", &config)
+        .env("ALBY_LOG_FILE", dir.join("env.log"))
+        .env("ALBY_TOR_DIR", dir.join("env-tor"))
+        .output()
+        .unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["logFile"]["detail"], dir.join("env.log").to_string_lossy().as_ref());
+    assert_eq!(report["torDir"]["detail"], dir.join("cli-tor").to_string_lossy().as_ref());
+
+    let output = Command::new(HOST)
+        .args(["doctor", "--json"])
+        .env("ALBY_CONFIG", &config)
+        .env_remove("ALBY_LOG_FILE")
+        .env_remove("ALBY_TOR_DIR")
+        .output()
+        .unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["logFile"]["detail"], dir.join("config.log").to_string_lossy().as_ref());
+    assert_eq!(report["torDir"]["detail"], dir.join("config-tor").to_string_lossy().as_ref());
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,71 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde_json::json;
+
+#[allow(dead_code)]
+#[path = "../src/framing.rs"]
+mod framing;
+
+use framing::{Host, parse_session, write_frame};
+
+const HOST: &str = env!("CARGO_BIN_EXE_alby");
+
+/// Own log file, lock, Tor folder and config, so the test doesn't meet a running companion
+fn get_test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("alby-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn get_host_args(dir: &Path) -> Vec<String> {
+    vec![
+        format!("--log-file={}", dir.join("alby.log").to_string_lossy()),
+        format!("--tor-dir={}", dir.join("tor").to_string_lossy()),
+        format!("--config={}", dir.join("config.json").to_string_lossy()),
+    ]
+}
+
+#[test]
+fn test_messages_over_stdio() {
+    let dir = get_test_dir("stdio");
+    let mut host = Host::spawn(Path::new(HOST), &get_host_args(&dir)).unwrap();
+    for id in ["1", "2"] {
+        write_frame(host.input.as_mut().unwrap(), &json!({ "id": id, "url": "", "method": "GET", "action": "diagnose" })).unwrap();
+        let res = host.receive().unwrap().unwrap();
+        assert_eq!(res["id"], id);
+        assert_eq!(res["status"], 200);
+        assert_eq!(res["headers"]["X-Alby-Internal"], "true");
+        let report: serde_json::Value = serde_json::from_str(res["body"].as_str().unwrap()).unwrap();
+        assert_eq!(report["version"], env!("CARGO_PKG_VERSION"));
+    }
+    // the end of input shuts the host down
+    assert!(host.close().unwrap().success());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_replay_session() {
+    let dir = get_test_dir("replay");
+    let session = dir.join("session.jsonl");
+    fs::write(&session, concat!(
+        r#"{"id": "a", "url": "", "method": "GET", "action": "diagnose"}"#, "\n",
+        r#"{"id": "b", "url": "", "method": "GET", "action": "diagnose", "body": "{}"}"#, "\n",
+    )).unwrap();
+    let output = Command::new(HOST)
+        .arg("replay")
+        .arg(&session)
+        .arg("--")
+        .args(get_host_args(&dir))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let responses = parse_session(&output.stdout).unwrap();
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[0]["id"], "a");
+    assert_eq!(responses[1]["id"], "b");
+    assert_eq!(responses[1]["status"], 200);
+    let _ = fs::remove_dir_all(&dir);
+}